@@ -1,4 +1,7 @@
 use core::fmt;
+use std::path::PathBuf;
+
+use crate::{scanner::Span, units::Rational};
 
 #[derive(Debug, PartialEq, Clone)]
 /// Errors that occur when parsing the tokens and creating score
@@ -6,6 +9,9 @@ pub enum ParseError {
     /// Occurs when specifying a tempo without the numbers per minute.
     /// For example: `q =`
     MissingTempoSpecifier,
+    /// Occurs when the note of a relative tempo change is longer than 4 whole notes, which is
+    /// more likely a tempo written without parentheses. For example: `q = 184/2`
+    TempoRatio,
     /// Occurs when a number is used outside of the context of ratios or tempo specifications
     Number,
     /// Occurs when a dot is found outside of the context of extending ratio duration
@@ -17,6 +23,58 @@ pub enum ParseError {
     /// Occurs when a bar repeat occurs anywhere else but after a
     /// [barline][crate::scanner::Token::Barline]
     BarRepeat,
+    /// Occurs when a rest marker is not directly preceded by a ratio or duration.
+    /// For example: `| r |`
+    Rest,
+    /// Occurs when an accent is not attached to a note. `>` and `^` must come directly before a
+    /// note while `'` and `_` must come directly after one
+    Accent,
+    /// Occurs when a time signature is not written as a ratio with an optional grouping.
+    /// For example: `#time q` or `#time 7/8 (2+2`
+    TimeSignature,
+    /// Occurs when the beat groups of a time signature do not add up to its numerator.
+    /// For example: `#time 7/8 (2+2)`
+    TimeSignatureGroups,
+    /// Occurs when a time signature is declared after the first note of a bar
+    TimeSignaturePlacement,
+    /// Occurs when a tempo ramp is not written as `<note> = <number> ~> <number>`, optionally
+    /// followed by `@<number of bars>`, or follows a relative tempo change
+    Ramp,
+    /// Occurs when a count-in is not written as `#count-in <number of bars>` before the first note
+    /// of the score
+    CountIn,
+    /// Occurs when a subdivision is declared after the first note of a bar
+    SubdividePlacement,
+    /// Occurs when a `+` is used outside of a time signature
+    Grouping,
+    /// Occurs when a group of notes opened with `(` is not closed with `)` in the same bar
+    UnclosedGroup,
+    /// Occurs when a `)` closes no group of notes or is not followed by the number of repeats of
+    /// the group. For example: `| (q e) q. |`
+    GroupRepeat,
+    /// Occurs when the length of a bar does not match its time signature. The bar is counted from
+    /// 1 and the lengths are in whole notes.
+    BarLength {
+        bar: usize,
+        expected: Rational,
+        actual: Rational,
+    },
+    /// Occurs when a rehearsal mark is written after the first note of a bar or after the last bar
+    SectionPlacement,
+    /// Occurs when a `|:` is not closed by a `:|`
+    UnclosedRepeat,
+    /// Occurs when a `:|` has no `|:` before it to go back to
+    UnopenedRepeat,
+    /// Occurs when a `|:` comes inside a passage that is already repeated
+    NestedRepeat,
+    /// Occurs when a `|:` is closed by a `:|` without any bar in between
+    EmptyRepeat,
+    /// Occurs when a first ending `[1]` inside or right after a repeat doesn't come before the
+    /// first note of a bar between `|:` and `:|`, or comes twice
+    FirstEnding,
+    /// Occurs when a second ending `[2]` inside or right after a repeat doesn't come directly
+    /// after the `:|` of a repeat with a first ending
+    SecondEnding,
     /// Occurs when the last bar has no closing
     /// [barline][crate::scanner::Token::Barline]
     UnterminatedBar,
@@ -28,8 +86,6 @@ pub enum ConversionError {
     /// Occurs when trying to convert a token to a [Duration][crate::score::Duration] that isn't a
     /// [Token::Ratio][crate::scanner::Token::Ratio]
     NonRatioToDuration,
-    /// Occurs when using a non ratio in a conversion that requires one
-    NonRatio,
 }
 
 #[derive(Debug, PartialEq, Clone)]
@@ -48,78 +104,423 @@ pub enum TokenError {
     LeadingSlash,
     /// Occurs when the number of repeats is less than 2 (i.e. 1)
     NotEnoughRepeats,
+    /// Occurs when the word after a `#` is not a known directive
+    UnknownDirective(String),
+    /// Occurs when a `~` is not followed by `>` or `~>`
+    IncompleteRamp,
+    /// Occurs when a number is larger than 65535
+    NumberTooLarge,
+    /// Occurs when a repeat of a group of bars is not written like `%[4]x3`
+    IncompleteBarRepeat,
+    /// Occurs when a `[` is not closed by a `]` on the same line
+    UnclosedSection,
+    /// Occurs when a rehearsal mark has no name, like `[]`
+    EmptySection,
 }
 
 #[derive(Debug, PartialEq, Clone)]
-/// The main error type that wraps [ParseError], [ConversionError] and [TokenError]
+/// The main error type that wraps [ParseError], [ConversionError] and [TokenError] together with
+/// the [Span] in the score where the error occurred
 pub enum MetrumError {
     /// see [ParseError]
-    ParseError(ParseError),
+    ParseError(ParseError, Span),
     /// see [ConversionError]
-    ConversionError(ConversionError),
+    ConversionError(ConversionError, Span),
     /// see [TokenError]
-    TokenError(TokenError),
+    TokenError(TokenError, Span),
+}
+
+impl MetrumError {
+    /// The location in the score where the error occurred
+    pub fn span(&self) -> Span {
+        match self {
+            MetrumError::ParseError(_, span)
+            | MetrumError::ConversionError(_, span)
+            | MetrumError::TokenError(_, span) => *span,
+        }
+    }
+
+    /// Renders the error together with the offending line of `source` and a caret underneath the
+    /// location of the error.
+    pub fn diagnostic(&self, source: &str) -> String {
+        let span = self.span();
+        let line = source
+            .lines()
+            .nth(span.line.saturating_sub(1))
+            .unwrap_or("");
+        let width = source
+            .get(span.start..span.end)
+            .map(|s| s.chars().count())
+            .unwrap_or(0)
+            .max(1);
+        let gutter = span.line.to_string();
+        let padding = " ".repeat(gutter.len());
+        format!(
+            "{self}\n{padding} |\n{gutter} | {line}\n{padding} | {}{}",
+            " ".repeat(span.column.saturating_sub(1)),
+            "^".repeat(width)
+        )
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParseError::MissingTempoSpecifier => {
+                write!(
+                    f,
+                    "A number, a ratio in parentheses or a note must come after '=' when \
+                     specifying tempo"
+                )
+            }
+            ParseError::TempoRatio => {
+                write!(
+                    f,
+                    "A note after '=' can't be longer than 4 whole notes, put a tempo in \
+                     parentheses like '(185/2)'"
+                )
+            }
+            ParseError::Number => {
+                write!(
+                    f,
+                    "A number can only be used in the context of ratios or tempo specifications"
+                )
+            }
+            ParseError::Dot => {
+                write!(f, "A dot can only be used after a ratio or duration")
+            }
+            ParseError::Equal => {
+                write!(
+                    f,
+                    "An equal character can only be used when specifying a tempo"
+                )
+            }
+            ParseError::NothingToRepeat => {
+                write!(f, "There is nothing to repeat!")
+            }
+            ParseError::BarRepeat => {
+                write!(f, "A bar repeat must come directly after a barline")
+            }
+            ParseError::Rest => {
+                write!(
+                    f,
+                    "A rest marker can only be used after a ratio or duration"
+                )
+            }
+            ParseError::Accent => {
+                write!(
+                    f,
+                    "'>' and '^' must come directly before a note, '\'' and '_' directly after one"
+                )
+            }
+            ParseError::TimeSignature => {
+                write!(
+                    f,
+                    "A time signature must be a ratio with an optional grouping, like '#time 7/8 (2+2+3)'"
+                )
+            }
+            ParseError::TimeSignatureGroups => {
+                write!(
+                    f,
+                    "The beat groups of a time signature must add up to its numerator"
+                )
+            }
+            ParseError::TimeSignaturePlacement => {
+                write!(
+                    f,
+                    "A time signature must come before the first note of a bar"
+                )
+            }
+            ParseError::Ramp => {
+                write!(
+                    f,
+                    "A tempo ramp must be written like 'q = 100 ~> 140' or 'q = 100 ~> 140 @4'"
+                )
+            }
+            ParseError::CountIn => {
+                write!(
+                    f,
+                    "A count-in must be written like '#count-in 2' before the first note of the score"
+                )
+            }
+            ParseError::SubdividePlacement => {
+                write!(f, "A subdivision must come before the first note of a bar")
+            }
+            ParseError::Grouping => {
+                write!(
+                    f,
+                    "'+' can only be used to group the beats of a time signature"
+                )
+            }
+            ParseError::UnclosedGroup => {
+                write!(
+                    f,
+                    "The group of notes opened by '(' must be closed by a ')' in the same bar"
+                )
+            }
+            ParseError::GroupRepeat => {
+                write!(
+                    f,
+                    "A ')' must close a group of notes opened by '(' and be followed by the number of repeats, like '(q. e)x4'"
+                )
+            }
+            ParseError::BarLength {
+                bar,
+                expected,
+                actual,
+            } => {
+                write!(
+                    f,
+                    "Bar {bar} is {actual} of a whole note long, but its time signature expects {expected}"
+                )
+            }
+            ParseError::SectionPlacement => {
+                write!(
+                    f,
+                    "A rehearsal mark must come before the first note of a bar"
+                )
+            }
+            ParseError::UnclosedRepeat => {
+                write!(f, "The repeat sign '|:' is never closed by a ':|'")
+            }
+            ParseError::UnopenedRepeat => {
+                write!(
+                    f,
+                    "The repeat sign ':|' has no '|:' before it to go back to"
+                )
+            }
+            ParseError::EmptyRepeat => {
+                write!(
+                    f,
+                    "The repeat sign '|:' has no bars before its ':|' to repeat"
+                )
+            }
+            ParseError::NestedRepeat => {
+                write!(
+                    f,
+                    "The repeat sign '|:' comes inside a passage that is already repeated, nested repeats are not supported"
+                )
+            }
+            ParseError::FirstEnding => {
+                write!(
+                    f,
+                    "The first ending '[1]' must come before the first note of a bar between '|:' and ':|'"
+                )
+            }
+            ParseError::SecondEnding => {
+                write!(
+                    f,
+                    "The second ending '[2]' must come directly after the ':|' of a repeat with a first ending"
+                )
+            }
+            ParseError::UnterminatedBar => {
+                write!(f, "A score must end with a barline")
+            }
+        }
+    }
+}
+
+impl fmt::Display for ConversionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConversionError::NonRatioToDuration => {
+                write!(f, "Cannot convert a non ratio to a duration")
+            }
+        }
+    }
+}
+
+impl fmt::Display for TokenError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TokenError::Zero => {
+                write!(f, "Use of 0 as a number of a ratio is not allowed")
+            }
+            TokenError::InvalidCharacter(c) => {
+                write!(f, "Use of Invalid Character: {c}")
+            }
+            TokenError::MissingRepetition(c) => {
+                write!(
+                    f,
+                    "The number of repetitions must be specified directly after the {c} character"
+                )
+            }
+            TokenError::IncompleteRatio => {
+                write!(f, "A number must come directly after a '/' in ratios")
+            }
+            TokenError::LeadingSlash => {
+                write!(
+                    f,
+                    "A '/' cannot come on its own without a number preceding it"
+                )
+            }
+            TokenError::NotEnoughRepeats => {
+                write!(f, "The number of repeats must be greater than 1")
+            }
+            TokenError::UnknownDirective(name) => {
+                write!(f, "Unknown directive: #{name}")
+            }
+            TokenError::IncompleteRamp => {
+                write!(f, "A tempo ramp must be written as '~>' or '~~>'")
+            }
+            TokenError::NumberTooLarge => {
+                write!(f, "Numbers cannot be larger than {}", u16::MAX)
+            }
+            TokenError::IncompleteBarRepeat => {
+                write!(
+                    f,
+                    "A repeat of a group of bars must be written like '%[4]x3', with the number of bars and how many times to play them"
+                )
+            }
+            TokenError::UnclosedSection => {
+                write!(
+                    f,
+                    "A rehearsal mark must be closed by a ']' on the same line"
+                )
+            }
+            TokenError::EmptySection => {
+                write!(f, "A rehearsal mark must have a name, like '[A]'")
+            }
+        }
+    }
 }
+
 impl fmt::Display for MetrumError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let span = self.span();
+        match self {
+            MetrumError::ParseError(e, _) => write!(f, "{e}")?,
+            MetrumError::ConversionError(e, _) => write!(f, "{e}")?,
+            MetrumError::TokenError(e, _) => write!(f, "{e}")?,
+        }
+        write!(f, " (line {}, column {})", span.line, span.column)
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+impl std::error::Error for ConversionError {}
+
+impl std::error::Error for TokenError {}
+
+impl std::error::Error for MetrumError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            MetrumError::ParseError(e, _) => Some(e),
+            MetrumError::ConversionError(e, _) => Some(e),
+            MetrumError::TokenError(e, _) => Some(e),
+        }
+    }
+}
+
+#[derive(Debug)]
+/// Errors that occur when loading the click sounds of a
+/// [Metronome][crate::writer::Metronome] from a sound pack
+pub enum SoundError {
+    /// Occurs when a sample cannot be read or is not a valid wav file
+    Sample(PathBuf, hound::Error),
+    /// Occurs when a sample contains no audio
+    EmptySample(PathBuf),
+    /// Occurs when a manifest cannot be read
+    Manifest(PathBuf, std::io::Error),
+    /// Occurs when a line of a manifest is not written as `<sound> = <path>` or names an unknown
+    /// sound. The line is counted from 1.
+    ManifestLine(usize, String),
+    /// Occurs when a sound pack has no sample for a sound that is required
+    MissingSound(&'static str),
+}
+
+impl fmt::Display for SoundError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SoundError::Sample(path, e) => write!(f, "{}: {e}", path.display()),
+            SoundError::EmptySample(path) => {
+                write!(f, "{}: The sample contains no audio", path.display())
+            }
+            SoundError::Manifest(path, e) => write!(f, "{}: {e}", path.display()),
+            SoundError::ManifestLine(line, text) => {
+                write!(
+                    f,
+                    "Line {line} of the manifest must be written as '<sound> = <path>' with a known sound: {text}"
+                )
+            }
+            SoundError::MissingSound(sound) => {
+                write!(f, "The sound pack has no sample for the {sound} sound")
+            }
+        }
+    }
+}
+
+impl std::error::Error for SoundError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            SoundError::Sample(_, e) => Some(e),
+            SoundError::Manifest(_, e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+/// Errors that occur when cutting a range of bars out of a [Score][crate::score::Score]
+pub enum SliceError {
+    /// Occurs when a range of bars is empty or reaches past the end of the score. Bars are counted
+    /// from 1.
+    Bars {
+        first: usize,
+        last: usize,
+        bars: usize,
+    },
+    /// Occurs when no rehearsal mark has the name of the section
+    UnknownSection(String),
+}
+
+impl fmt::Display for SliceError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
-            MetrumError::ParseError(e) => match e {
-                ParseError::MissingTempoSpecifier => {
-                    write!(f, "A number must come after '=' when specifying tempo")
-                }
-                ParseError::Number => {
-                    write!(f, "A number can only be used in the context of ratios or tempo specifications")
-                }
-                ParseError::Dot => {
-                    write!(f, "A dot can only be used after a ratio or duration")
-                }
-                ParseError::Equal => {
-                    write!(
-                        f,
-                        "An equal character can only be used when specifying a tempo"
-                    )
-                }
-                ParseError::NothingToRepeat => {
-                    write!(f, "There is nothing to repeat!")
-                }
-                ParseError::BarRepeat => {
-                    write!(f, "A bar repeat must come directly after a barline")
-                }
-                ParseError::UnterminatedBar => {
-                    write!(f, "A score must end with a barline")
-                }
-            },
-            MetrumError::ConversionError(e) => match e {
-                ConversionError::NonRatioToDuration => {
-                    write!(f, "Cannot convert a non ratio to a duration")
-                }
-                ConversionError::NonRatio => {
-                    write!(f, "Cannot use a non ratio in this conversion")
-                }
-            },
-            MetrumError::TokenError(e) => match e {
-                TokenError::Zero => {
-                    write!(f, "Use of 0 as a number of a ratio is not allowed")
-                }
-                TokenError::InvalidCharacter(c) => {
-                    write!(f, "Use of Invalid Character: {c}")
-                }
-                TokenError::MissingRepetition(c) => {
-                    write!(f, "The number of repetitions must be specified directly after the {c} character")
-                }
-                TokenError::IncompleteRatio => {
-                    write!(f, "A number must come directly after a '/' in ratios")
-                }
-                TokenError::LeadingSlash => {
-                    write!(
-                        f,
-                        "A '/' cannot come on its own without a number preceding it"
-                    )
-                }
-                TokenError::NotEnoughRepeats => {
-                    write!(f, "The number of repeats must be greater than 1")
-                }
-            },
+            SliceError::Bars { first, last, bars } => {
+                write!(
+                    f,
+                    "Bars {first} to {last} are not in the score, which has bars 1 to {bars}"
+                )
+            }
+            SliceError::UnknownSection(name) => {
+                write!(f, "The score has no section named [{name}]")
+            }
         }
     }
 }
+
+impl std::error::Error for SliceError {}
+
+#[derive(Debug, PartialEq, Clone)]
+/// Errors that occur when changing the tempo of a [Score][crate::score::Score]
+pub enum TempoError {
+    /// Occurs when a tempo, given in beats per minute, would drop to 0 or below
+    NotPositive(Rational),
+    /// Occurs when a tempo, given in beats per minute, would become too fast to be represented
+    TooFast(Rational),
+}
+
+impl fmt::Display for TempoError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TempoError::NotPositive(tempo) => {
+                write!(
+                    f,
+                    "The tempo of {} beats per minute would drop to 0 or below",
+                    tempo.as_f64()
+                )
+            }
+            TempoError::TooFast(tempo) => {
+                write!(
+                    f,
+                    "The tempo of {} beats per minute would become too fast",
+                    tempo.as_f64()
+                )
+            }
+        }
+    }
+}
+
+impl std::error::Error for TempoError {}
@@ -2,10 +2,18 @@
 
 /// Metrome error types
 pub mod error;
+/// Exports the click track as a Standard MIDI File
+pub mod midi;
 /// Scans and tokenizes a Metrome score
 pub mod scanner;
 /// This module contains structs that are related to the representation of a score.
 pub mod score;
+/// Cuts passages out of a score and loops them for practice
+pub mod slice;
+/// Synthesizes click sounds
+pub mod synth;
+/// Exports the timeline of the click track as text, such as tempo maps
+pub mod timeline;
 /// Unit conversion utilities
 pub mod units;
 /// Writes the click track from a score
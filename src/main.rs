@@ -1,27 +1,323 @@
-use clap::Parser;
-use metrome::{scanner, score::Score};
+use std::io::{Cursor, Write};
+use std::path::PathBuf;
+use std::process::ExitCode;
+
+use clap::{Parser, ValueEnum};
+use metrome::{
+    scanner,
+    score::Score,
+    slice::Practice,
+    synth::{SynthClicks, Voice},
+    units::Rational,
+    writer::{BitDepth, Metronome, RenderOptions},
+};
 
 #[derive(Parser, Debug)]
 #[command(author,version,about,long_about=None)]
 struct Args {
+    /// Path to the score. Reads the score from stdin if it is "-" or left out
     #[arg(short, long)]
-    path: String,
+    path: Option<String>,
+    /// Path to write the click track to. Writes to stdout if it is "-" or if the score is read
+    /// from stdin and no output is given
     #[arg(short, long)]
     output: Option<String>,
+    /// Number of bars to count in before the first bar, overriding the score's #count-in
+    #[arg(short, long)]
+    count_in: Option<u16>,
+    /// Use a distinct click for the count-in
+    #[arg(long)]
+    distinct_count_in: bool,
+    /// Number of subdivision clicks per beat in every bar, overriding the score's #subdivide.
+    /// Use 0 to turn subdivisions off
+    #[arg(short, long)]
+    subdivide: Option<u16>,
+    /// Scales every tempo of the score, like 0.8 for 80% of the written tempo
+    #[arg(long, value_parser = parse_scale)]
+    tempo_scale: Option<Rational>,
+    /// Adds the given number of beats per minute to every tempo written in the score, like -10
+    #[arg(long, allow_negative_numbers = true)]
+    tempo_offset: Option<f64>,
+    /// Renders only the given bars, counted from 1, like "34-52" or "34"
+    #[arg(long, value_parser = parse_bars, conflicts_with = "section")]
+    bars: Option<(usize, usize)>,
+    /// Renders only the section with the given rehearsal mark, like "B"
+    #[arg(long)]
+    section: Option<String>,
+    /// Number of times to play the score, or the bars or section to render, in a row
+    #[arg(long = "loop", value_parser = clap::value_parser!(u16).range(1..))]
+    times: Option<u16>,
+    /// Plays the score, or the bars or section to render, in passes that start at the given
+    /// percentage of the tempo and get faster until they reach the tempo of the score. Use --loop
+    /// to set the number of passes
+    #[arg(long, value_parser = clap::value_parser!(u16).range(1..))]
+    practice: Option<u16>,
+    /// Percentage of the tempo every practice pass is faster than the one before
+    #[arg(long, default_value_t = 5, requires = "practice")]
+    step: u16,
+    /// Sample rate of the click track in Hz
+    #[arg(short = 'r', long, default_value_t = 44100, value_parser = clap::value_parser!(u32).range(1..))]
+    sample_rate: u32,
+    /// Sample format of the click track
+    #[arg(short, long, value_enum, default_value_t = Depth::Int16)]
+    bit_depth: Depth,
+    /// Number of channels of the click track
+    #[arg(long, default_value_t = 1, value_parser = clap::value_parser!(u16).range(1..))]
+    channels: u16,
+    /// Pans the click from -1.0 (left) to 1.0 (right) when there are two or more channels
+    #[arg(long, default_value_t = 0.0, allow_negative_numbers = true, value_parser = parse_pan)]
+    pan: f32,
+    /// Also writes the click track as a MIDI file with the tempo map to the given path
+    #[arg(long)]
+    midi: Option<String>,
+    /// Also writes the tempo map to the given path, as a MIDI file if the path ends in .mid or
+    /// .midi and as CSV otherwise
+    #[arg(long)]
+    tempo_map: Option<String>,
+    /// Also writes a label for every bar to the given path, as a JSON timeline if the path ends
+    /// in .json, as a Reaper marker list if it ends in .csv and as an Audacity label track
+    /// otherwise
+    #[arg(long)]
+    labels: Option<String>,
+    /// Directory or manifest of a sound pack to use instead of the built-in clicks
+    #[arg(long, conflicts_with = "voice")]
+    sounds: Option<PathBuf>,
+    /// Synthesizes the clicks with the given voice instead of using the built-in clicks
+    #[arg(long, value_enum)]
+    voice: Option<SynthVoice>,
+    /// Pitch of the synthesized clicks in Hz. The downbeat sounds a fifth higher
+    #[arg(long, requires = "voice")]
+    pitch: Option<f32>,
+    /// Decay time of the synthesized clicks in milliseconds
+    #[arg(long, requires = "voice")]
+    decay: Option<f32>,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum SynthVoice {
+    Sine,
+    Woodblock,
+    Cowbell,
+    Rimshot,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum Depth {
+    /// 16-bit integer samples
+    #[value(name = "16")]
+    Int16,
+    /// 24-bit integer samples
+    #[value(name = "24")]
+    Int24,
+    /// 32-bit floating point samples
+    #[value(name = "32f")]
+    Float32,
+}
+
+fn parse_pan(s: &str) -> Result<f32, String> {
+    match s.parse::<f32>() {
+        Ok(pan) if (-1.0..=1.0).contains(&pan) => Ok(pan),
+        _ => Err("pan must be a number from -1.0 to 1.0".to_string()),
+    }
+}
+
+fn parse_scale(s: &str) -> Result<Rational, String> {
+    match s
+        .parse::<f64>()
+        .ok()
+        .and_then(Rational::approximate_positive)
+    {
+        Some(scale) => Ok(scale),
+        _ => Err("the tempo scale must be a number from 0.000000001 to 10000000000".to_string()),
+    }
 }
 
-fn main() -> Result<(), hound::Error> {
+fn parse_bars(s: &str) -> Result<(usize, usize), String> {
+    let (first, last) = s.split_once('-').unwrap_or((s, s));
+    match (first.trim().parse::<usize>(), last.trim().parse::<usize>()) {
+        (Ok(first), Ok(last)) => Ok((first, last)),
+        _ => Err("bars must be a bar number or a range of bars like 34-52".to_string()),
+    }
+}
+
+/// The arguments are invalid, the same exit code clap uses
+const EXIT_USAGE: u8 = 2;
+/// The score or the sound pack could not be read
+const EXIT_IO: u8 = 3;
+/// The score contains an error
+const EXIT_SYNTAX: u8 = 4;
+/// The click track could not be written
+const EXIT_RENDER: u8 = 5;
+
+fn main() -> ExitCode {
     let args = Args::parse();
-    let file = std::fs::read_to_string(&args.path).unwrap();
+    let path = args.path.as_deref().filter(|p| *p != "-");
+    let name = path.unwrap_or("<stdin>");
+    let read = match path {
+        Some(path) => std::fs::read_to_string(path),
+        None => std::io::read_to_string(std::io::stdin()),
+    };
+    let file = match read {
+        Ok(file) => file,
+        Err(e) => {
+            eprintln!("{name}: {e}");
+            return ExitCode::from(EXIT_IO);
+        }
+    };
     let separator = match cfg!(target_os = "windows") {
         true => '\\',
         _ => '/',
     };
-    let output_path = match &args.output {
-        Some(path) => path.clone(),
-        None => format!("{}.wav", &args.path.split(separator).last().unwrap()),
+    let output_path = match (&args.output, path) {
+        (Some(output), _) => Some(output.clone()).filter(|o| o != "-"),
+        (None, Some(path)) => Some(format!(
+            "{}.wav",
+            path.split(separator).next_back().unwrap()
+        )),
+        (None, None) => None,
+    };
+    let mut score = match scanner::scan(file.clone()).and_then(Score::new) {
+        Ok(score) => score,
+        Err(e) => {
+            eprintln!("{name}: {}", e.diagnostic(&file));
+            return ExitCode::from(EXIT_SYNTAX);
+        }
+    };
+    if let Some(scale) = args.tempo_scale {
+        if let Err(e) = score.scale_tempo(scale) {
+            eprintln!("{name}: {e}");
+            return ExitCode::from(EXIT_USAGE);
+        }
+    }
+    if let Some(offset) = args.tempo_offset {
+        if let Err(e) = score.offset_tempo(offset) {
+            eprintln!("{name}: {e}");
+            return ExitCode::from(EXIT_USAGE);
+        }
+    }
+    let sliced = match (args.bars, &args.section) {
+        (Some((first, last)), _) => score.slice(first..=last).map(Some),
+        (None, Some(section)) => score.section(section).map(Some),
+        (None, None) => Ok(None),
+    };
+    match sliced {
+        Ok(Some(slice)) => score = slice,
+        Ok(None) => {}
+        Err(e) => {
+            eprintln!("{name}: {e}");
+            return ExitCode::from(EXIT_USAGE);
+        }
+    }
+    match args.practice {
+        Some(start) => {
+            let practice = Practice {
+                start,
+                step: args.step,
+            };
+            match score.practice(&practice, args.times.unwrap_or(practice.passes())) {
+                Ok(passes) => score = passes,
+                Err(e) => {
+                    eprintln!("{name}: {e}");
+                    return ExitCode::from(EXIT_USAGE);
+                }
+            }
+        }
+        None => {
+            if let Some(times) = args.times {
+                score = score.looped(times);
+            }
+        }
+    }
+    if let Some(count_in) = args.count_in {
+        score.count_in = count_in;
+    }
+    if let Some(subdivide) = args.subdivide {
+        score.set_subdivision(Some(subdivide).filter(|n| *n > 0));
+    }
+    let mut metronome = match &args.sounds {
+        Some(sounds) => match Metronome::from_path(sounds) {
+            Ok(metronome) => metronome,
+            Err(e) => {
+                eprintln!("{}: {e}", sounds.display());
+                return ExitCode::from(EXIT_IO);
+            }
+        },
+        None => match args.voice {
+            Some(voice) => {
+                let voice = match voice {
+                    SynthVoice::Sine => Voice::Sine,
+                    SynthVoice::Woodblock => Voice::Woodblock,
+                    SynthVoice::Cowbell => Voice::Cowbell,
+                    SynthVoice::Rimshot => Voice::Rimshot,
+                };
+                let mut clicks =
+                    SynthClicks::new(voice, args.pitch.unwrap_or(voice.default_pitch()));
+                if let Some(decay) = args.decay {
+                    for click in [
+                        &mut clicks.downbeat,
+                        &mut clicks.secondary,
+                        &mut clicks.unaccented,
+                        &mut clicks.ghost,
+                        &mut clicks.subdivision,
+                    ] {
+                        click.decay = decay / 1000.0;
+                    }
+                }
+                Metronome::synthesized(&clicks, args.sample_rate)
+            }
+            None => Metronome::new(),
+        },
+    };
+    if args.distinct_count_in {
+        metronome = metronome.with_distinct_count_in();
+    }
+    if let Some(midi) = &args.midi {
+        if let Err(e) = score.write_midi(midi) {
+            eprintln!("{midi}: {e}");
+            return ExitCode::from(EXIT_RENDER);
+        }
+    }
+    if let Some(tempo_map) = &args.tempo_map {
+        let written = match tempo_map.ends_with(".mid") || tempo_map.ends_with(".midi") {
+            true => score.write_tempo_map_midi(tempo_map),
+            false => score.write_tempo_map_csv(tempo_map),
+        };
+        if let Err(e) = written {
+            eprintln!("{tempo_map}: {e}");
+            return ExitCode::from(EXIT_RENDER);
+        }
+    }
+    if let Some(labels) = &args.labels {
+        if let Err(e) = score.write_labels(labels) {
+            eprintln!("{labels}: {e}");
+            return ExitCode::from(EXIT_RENDER);
+        }
+    }
+    let options = RenderOptions {
+        sample_rate: args.sample_rate,
+        bit_depth: match args.bit_depth {
+            Depth::Int16 => BitDepth::Int16,
+            Depth::Int24 => BitDepth::Int24,
+            Depth::Float32 => BitDepth::Float32,
+        },
+        channels: args.channels,
+        pan: args.pan,
+    };
+    let written = match &output_path {
+        Some(output_path) => score.write_click_track_with(output_path, &metronome, &options),
+        // stdout can't seek, so the wav file is put together in memory first
+        None => {
+            let mut buffer = Cursor::new(Vec::new());
+            score
+                .write_click_track_to(&mut buffer, &metronome, &options)
+                .and_then(|_| Ok(std::io::stdout().write_all(buffer.get_ref())?))
+        }
     };
-    let tokens = scanner::scan(file).unwrap();
-    let score = Score::new(tokens).unwrap();
-    score.write_click_track(&output_path)
+    match written {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("{}: {e}", output_path.as_deref().unwrap_or("<stdout>"));
+            ExitCode::from(EXIT_RENDER)
+        }
+    }
 }
@@ -0,0 +1,411 @@
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+
+use crate::score::{Accent, ClickKind, Meter, Score};
+use crate::units::Rational;
+
+/// Ticks per quarter note. Divisible by 3 and 5 so that most tuplets land exactly on a tick.
+pub const TICKS_PER_QUARTER: u16 = 960;
+
+/// Ramps are written as a tempo change every sixteenth note
+const RAMP_STEPS_PER_WHOLE: u64 = 16;
+
+/// The channel GM percussion plays on, counted from 0
+const PERCUSSION_CHANNEL: u8 = 9;
+
+/// The GM percussion note and the velocity of a click
+fn note_of(kind: ClickKind) -> Option<(u8, u8)> {
+    let accent = match kind {
+        ClickKind::CountIn(accent) | ClickKind::Note(accent) => accent,
+        // Closed Hi-Hat
+        ClickKind::Subdivision => return Some((42, 50)),
+    };
+    match accent {
+        // Hi Wood Block
+        Accent::Downbeat => Some((76, 127)),
+        Accent::Secondary => Some((76, 90)),
+        // Low Wood Block
+        Accent::Unaccented => Some((77, 100)),
+        Accent::Ghost => Some((77, 45)),
+        Accent::Silent => None,
+    }
+}
+
+/// Converts a position in whole notes to ticks, rounded to the nearest tick
+fn ticks(position: Rational) -> u32 {
+    let ticks = position * Rational::from(4 * TICKS_PER_QUARTER as u64);
+    ((ticks.numer() + ticks.denom() / 2) / ticks.denom()) as u32
+}
+
+/// Appends a number as a MIDI variable length quantity
+fn push_vlq(bytes: &mut Vec<u8>, n: u32) {
+    let mut groups = vec![(n & 0x7f) as u8];
+    let mut n = n >> 7;
+    while n > 0 {
+        groups.push((n & 0x7f) as u8 | 0x80);
+        n >>= 7;
+    }
+    bytes.extend(groups.iter().rev());
+}
+
+/// A meta event holding text, such as a track name or a marker
+fn text_event(kind: u8, text: &str) -> Vec<u8> {
+    let mut event = vec![0xff, kind];
+    push_vlq(&mut event, text.len() as u32);
+    event.extend(text.bytes());
+    event
+}
+
+/// A track chunk that is assembled from events at absolute ticks
+struct Track {
+    /// Events with their tick and a rank that orders events at the same tick
+    events: Vec<(u32, u8, Vec<u8>)>,
+}
+
+impl Track {
+    fn new(name: &str) -> Self {
+        Track {
+            events: vec![(0, 0, text_event(0x03, name))],
+        }
+    }
+
+    fn push(&mut self, tick: u32, rank: u8, event: Vec<u8>) {
+        self.events.push((tick, rank, event));
+    }
+
+    fn into_chunk(mut self) -> Vec<u8> {
+        // the sort is stable, so events of the same tick and rank keep their order
+        self.events.sort_by_key(|(tick, rank, _)| (*tick, *rank));
+        let mut data = Vec::new();
+        let mut last = 0;
+        for (tick, _, event) in self.events.iter() {
+            push_vlq(&mut data, tick - last);
+            data.extend(event);
+            last = *tick;
+        }
+        data.extend([0x00, 0xff, 0x2f, 0x00]);
+        let mut chunk = b"MTrk".to_vec();
+        chunk.extend((data.len() as u32).to_be_bytes());
+        chunk.extend(data);
+        chunk
+    }
+}
+
+/// Returns a time signature as its numerator, the power of two of its denominator and the
+/// number of MIDI clocks per beat. Time signatures that MIDI can't express, like `3/6`, are left
+/// out.
+fn time_signature(meter: &Meter) -> Option<(u8, u8, u8)> {
+    // compound meters are felt in their groups, like in the count-in
+    let groups = &meter.groups;
+    let beat = match meter.bottom >= 8 && groups.iter().all(|g| *g == groups[0]) {
+        true => groups[0],
+        false => 1,
+    };
+    let top = u8::try_from(meter.top).ok()?;
+    if !meter.bottom.is_power_of_two() {
+        return None;
+    }
+    let clocks = (96 * beat as u32 / meter.bottom as u32).clamp(1, u8::MAX as u32);
+    Some((top, meter.bottom.trailing_zeros() as u8, clocks as u8))
+}
+
+impl Score {
+    /// Returns the position in whole notes of every tempo change of the click track, counted
+    /// from the start of the count-in, with the tempo from there on in microseconds per quarter
+    /// note. Ramps are broken up into a tempo change per sixteenth note and per click, each tempo
+    /// chosen so that every change and every click lands at the same time as in the click track.
+    pub fn tempo_changes(&self) -> Vec<(Rational, u32)> {
+        let (count_in, count_in_ms) = self.count_in_length();
+        let mut segments = Vec::new();
+        if !count_in.is_zero() {
+            segments.push((Rational::zero(), count_in, count_in_ms));
+        }
+        let step = Rational::new(1, RAMP_STEPS_PER_WHOLE);
+        let clicks: Vec<Rational> = self
+            .clicks()
+            .iter()
+            .filter(|click| click.position >= count_in)
+            .map(|click| click.position - count_in)
+            .collect();
+        let mut breakpoints = Vec::new();
+        for mark in self.tempo_map.iter() {
+            breakpoints.push(mark.position);
+            if let Some(ramp) = mark.ramp {
+                let mut position = mark.position + step;
+                while position < ramp.end {
+                    breakpoints.push(position);
+                    position += step;
+                }
+                breakpoints.push(ramp.end);
+                // clicks during a ramp land exactly on a tempo change too
+                let during = |p: &&Rational| **p > mark.position && **p < ramp.end;
+                breakpoints.extend(clicks.iter().filter(during));
+            }
+        }
+        let length = self.length();
+        breakpoints.push(length);
+        breakpoints.retain(|position| *position <= length);
+        breakpoints.sort();
+        breakpoints.dedup();
+        for pair in breakpoints.windows(2) {
+            let ms = self.time_at(pair[1]) - self.time_at(pair[0]);
+            segments.push((count_in + pair[0], pair[1] - pair[0], ms));
+        }
+        let mut changes: Vec<(Rational, u32)> = Vec::new();
+        for (position, length, ms) in segments {
+            if length.is_zero() {
+                continue;
+            }
+            let quarters = length * Rational::from(4);
+            let micros = ms * Rational::from(1000) / quarters;
+            let micros = (micros.as_f64().round() as u32).clamp(1, 0xff_ffff);
+            if changes.last().is_none_or(|(_, last)| *last != micros) {
+                changes.push((position, micros));
+            }
+        }
+        changes
+    }
+
+    /// Builds the conductor track of a MIDI file, holding the tempo changes, the time signature
+    /// of every bar, including the count-in, and a marker for every rehearsal mark
+    fn conductor_track(&self) -> Track {
+        let mut track = Track::new("Tempo");
+        for (position, micros) in self.tempo_changes() {
+            let mut event = vec![0xff, 0x51, 0x03];
+            event.extend(&micros.to_be_bytes()[1..]);
+            track.push(ticks(position), 1, event);
+        }
+        let mut last = None;
+        for start in self.bar_starts() {
+            let signature = time_signature(&start.meter);
+            match signature {
+                Some((top, bottom, clocks)) if signature != last => {
+                    let event = vec![0xff, 0x58, 0x04, top, bottom, clocks, 8];
+                    track.push(ticks(start.position), 1, event);
+                    last = signature;
+                }
+                _ => {}
+            }
+            if let Some(section) = &start.section {
+                track.push(ticks(start.position), 1, text_event(0x06, section));
+            }
+        }
+        track
+    }
+
+    /// Creates a type 1 Standard MIDI File of the click track. The first track holds the tempo
+    /// map and the time signatures, the second a note on the GM percussion channel for every
+    /// click: wood blocks for notes and the count-in, a closed hi-hat for subdivisions.
+    pub fn midi_file(&self) -> Vec<u8> {
+        let mut clicks = Track::new("Click");
+        let mut notes: Vec<(u32, u8, u8)> = self
+            .clicks()
+            .iter()
+            .filter_map(|click| {
+                let (note, velocity) = note_of(click.kind)?;
+                Some((ticks(click.position), note, velocity))
+            })
+            .collect();
+        notes.sort_by_key(|(tick, ..)| *tick);
+        let gate = TICKS_PER_QUARTER as u32 / 8;
+        for (i, (tick, note, velocity)) in notes.iter().enumerate() {
+            // a note ends before the next click at the latest
+            let next = notes.get(i + 1).map_or(u32::MAX, |n| n.0);
+            let end = (tick + gate).min(next).max(tick + 1);
+            clicks.push(*tick, 1, vec![0x90 | PERCUSSION_CHANNEL, *note, *velocity]);
+            // note offs come before note ons at the same tick
+            clicks.push(end, 0, vec![0x80 | PERCUSSION_CHANNEL, *note, 0]);
+        }
+        midi_file(vec![self.conductor_track(), clicks])
+    }
+
+    /// Creates a Standard MIDI File holding nothing but the tempo map and the time signatures of
+    /// the click track, for importing into a DAW session
+    pub fn tempo_map_midi_file(&self) -> Vec<u8> {
+        midi_file(vec![self.conductor_track()])
+    }
+
+    /// Writes the click track as a Standard MIDI File, see [Score::midi_file]
+    pub fn write_midi(&self, path: &str) -> io::Result<()> {
+        write_file(path, &self.midi_file())
+    }
+
+    /// Writes the tempo map as a Standard MIDI File, see [Score::tempo_map_midi_file]
+    pub fn write_tempo_map_midi(&self, path: &str) -> io::Result<()> {
+        write_file(path, &self.tempo_map_midi_file())
+    }
+}
+
+fn write_file(path: &str, bytes: &[u8]) -> io::Result<()> {
+    let mut file = BufWriter::new(File::create(path)?);
+    file.write_all(bytes)?;
+    file.flush()
+}
+
+/// Assembles a Standard MIDI File from its tracks, of type 0 for a single track and of type 1
+/// otherwise
+fn midi_file(tracks: Vec<Track>) -> Vec<u8> {
+    let format: u16 = match tracks.len() {
+        1 => 0,
+        _ => 1,
+    };
+    let mut bytes = b"MThd".to_vec();
+    bytes.extend(6u32.to_be_bytes());
+    bytes.extend(format.to_be_bytes());
+    bytes.extend((tracks.len() as u16).to_be_bytes());
+    bytes.extend(TICKS_PER_QUARTER.to_be_bytes());
+    for track in tracks {
+        bytes.extend(track.into_chunk());
+    }
+    bytes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{push_vlq, ticks, time_signature, TICKS_PER_QUARTER};
+    use crate::{
+        scanner::scan,
+        score::{Meter, Score},
+        units::Rational,
+    };
+
+    fn score(s: &str) -> Score {
+        Score::new(scan(s.to_string()).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn variable_length_quantities() {
+        let data = [
+            (0, vec![0x00]),
+            (0x7f, vec![0x7f]),
+            (0x80, vec![0x81, 0x00]),
+            (0x2000, vec![0xc0, 0x00]),
+            (0x0fff_ffff, vec![0xff, 0xff, 0xff, 0x7f]),
+        ];
+        for (n, bytes) in data.iter() {
+            let mut out = Vec::new();
+            push_vlq(&mut out, *n);
+            assert_eq!(out, *bytes);
+        }
+    }
+
+    #[test]
+    fn positions_to_ticks() {
+        let data = [
+            (Rational::new(1, 4), 960),
+            (Rational::new(1, 6), 640),
+            (Rational::new(1, 7), 549),
+            (Rational::from(3), 11520),
+        ];
+        for (position, tick) in data.iter() {
+            assert_eq!(ticks(*position), *tick);
+        }
+    }
+
+    #[test]
+    fn time_signatures() {
+        let data = [
+            (Meter::new(4, 4), Some((4, 2, 24))),
+            (Meter::new(6, 8), Some((6, 3, 36))),
+            (Meter::new(5, 8), Some((5, 3, 60))),
+            (Meter::new(3, 6), None),
+            (Meter::from_length(Rational::new(5, 8)), Some((5, 3, 60))),
+            (Meter::from_length(Rational::from(1)), Some((4, 2, 24))),
+            (Meter::from_length(Rational::new(1, 3)), None),
+        ];
+        for (meter, signature) in data.iter() {
+            assert_eq!(time_signature(meter), *signature, "{meter}");
+        }
+    }
+
+    #[test]
+    fn tempo_changes() {
+        let data = [
+            ("| q |", vec![(Rational::zero(), 500_000)]),
+            (
+                "q = 60 | q | h = 60 | h |",
+                vec![
+                    (Rational::zero(), 1_000_000),
+                    (Rational::new(1, 4), 500_000),
+                ],
+            ),
+            (
+                "#count-in 1 q = 90 | q | q = 120 | q |",
+                vec![(Rational::zero(), 666_667), (Rational::new(1, 2), 500_000)],
+            ),
+        ];
+        for (s, changes) in data.iter() {
+            assert_eq!(score(s).tempo_changes(), *changes, "{s}");
+        }
+    }
+
+    #[test]
+    fn tempo_maps_follow_the_click_track() {
+        let data = [
+            "q = 60 ~> 120 @2 | q q q q | q q q q | q |",
+            "#count-in 2 q = 100 ~~> 40 | e e e e e e e e | 1/6x6 | q = 80 | h h |",
+            "#time 6/8 q. = 60 | e e e q. | q = q. | q q q |",
+        ];
+        for s in data.iter() {
+            let score = score(s);
+            let changes = score.tempo_changes();
+            // the time of a tick following the tempo changes, like a DAW would
+            let seconds = |tick: u32| {
+                let mut time = 0.0;
+                for (i, (position, micros)) in changes.iter().enumerate() {
+                    let start = ticks(*position);
+                    let end = changes.get(i + 1).map_or(u32::MAX, |c| ticks(c.0));
+                    if start >= tick {
+                        break;
+                    }
+                    let length = end.min(tick) - start;
+                    time += length as f64 * *micros as f64 / TICKS_PER_QUARTER as f64;
+                }
+                time / 1000.0
+            };
+            for click in score.clicks() {
+                let ms = seconds(ticks(click.position));
+                assert!((ms - click.ms.as_f64()).abs() < 0.01, "{s}: {click:?}");
+            }
+        }
+    }
+
+    #[test]
+    fn midi_files() {
+        let midi = score("#count-in 1 #subdivide 2 | q q_ qr q |").midi_file();
+        assert_eq!(&midi[..4], b"MThd");
+        // type 1, two tracks, ticks per quarter
+        assert_eq!(&midi[8..14], &[0, 1, 0, 2, 0x03, 0xc0]);
+        let note_ons = midi
+            .windows(2)
+            .filter(|w| w == &[0x99, 76] || w == &[0x99, 77]);
+        // four beats of count-in and two audible notes
+        assert_eq!(note_ons.count(), 6);
+        let subdivisions = midi.windows(2).filter(|w| w == &[0x99, 42]);
+        assert_eq!(subdivisions.count(), 5);
+        assert_eq!(midi.windows(4).filter(|w| w == b"MTrk").count(), 2);
+    }
+
+    #[test]
+    fn tempo_map_midi_files() {
+        let score = score("q = 100 #time 3/4 | q q q | #time 6/8 q. = 60 [B] | e e e e e e |");
+        let midi = score.tempo_map_midi_file();
+        // type 0, one track
+        assert_eq!(&midi[8..12], &[0, 0, 0, 1]);
+        assert_eq!(midi.windows(2).filter(|w| w[0] == 0x99).count(), 0);
+        // 100 quarters and 60 dotted quarters per minute
+        assert!(midi
+            .windows(6)
+            .any(|w| w == [0xff, 0x51, 0x03, 0x09, 0x27, 0xc0]));
+        assert!(midi
+            .windows(6)
+            .any(|w| w == [0xff, 0x51, 0x03, 0x0a, 0x2c, 0x2b]));
+        assert!(midi
+            .windows(7)
+            .any(|w| w == [0xff, 0x58, 0x04, 3, 2, 24, 8]));
+        assert!(midi
+            .windows(7)
+            .any(|w| w == [0xff, 0x58, 0x04, 6, 3, 36, 8]));
+        assert!(midi.windows(4).any(|w| w == [0xff, 0x06, 0x01, b'B']));
+    }
+}
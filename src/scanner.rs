@@ -1,98 +1,387 @@
-use crate::error::{MetrumError, TokenError};
+use std::{iter::Peekable, str::CharIndices};
 
-#[derive(Debug, Clone, Copy, PartialEq)]
+use crate::{
+    error::{MetrumError, TokenError},
+    score::{Accent, Curve},
+};
+
+#[derive(Debug, Clone, PartialEq)]
 pub enum Token {
     Barline,
     Ratio(u16, u16),
     NoteRepeat(u16),
-    BarRepeat(u16),
+    /// A bar repeat, holding how many of the last bars are repeated as a group and how many times
+    /// the group is played, e.g. `%4` is `BarRepeat(1, 4)` and `%[4]x3` is `BarRepeat(4, 3)`
+    BarRepeat(u16, u16),
     Number(u16),
+    /// A number with a decimal point, holding the whole part, the digits after the point and how
+    /// many digits there are, e.g. `92.25` is `Decimal(92, 25, 2)`
+    Decimal(u16, u32, u32),
     Equal,
     Dot,
+    Rest,
+    Accent(Accent),
+    Directive(Directive),
+    LeftParen,
+    RightParen,
+    Plus,
+    Ramp(Curve),
+    At,
+    /// A rehearsal mark naming the section that starts with the next bar, e.g. `[A]` or `[Coda]`
+    Section(String),
+    /// `|:`, a barline that starts a repeated passage
+    RepeatStart,
+    /// `:|`, a barline that ends a repeated passage
+    RepeatEnd,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+/// Keywords written after a `#` that change how the rest of the score is read
+pub enum Directive {
+    /// `#time`, declares the time signature of the following bars
+    Time,
+    /// `#count-in`, the number of bars to count in before the first bar
+    CountIn,
+    /// `#subdivide`, the number of subdivision clicks per beat in the following bars
+    Subdivide,
+}
+
+impl Directive {
+    fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "time" => Some(Directive::Time),
+            "count-in" => Some(Directive::CountIn),
+            "subdivide" => Some(Directive::Subdivide),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+/// The location of a token in the score. Lines and columns start at 1, columns are counted in
+/// characters and `start..end` is the byte range of the token in the source.
+pub struct Span {
+    pub line: usize,
+    pub column: usize,
+    pub start: usize,
+    pub end: usize,
+}
+
+impl Span {
+    /// Creates a span that covers both `self` and `other`
+    pub fn to(&self, other: Span) -> Span {
+        Span {
+            line: self.line,
+            column: self.column,
+            start: self.start,
+            end: other.end,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+/// A [Token] together with the [Span] it was scanned from
+pub struct Spanned {
+    pub token: Token,
+    pub span: Span,
+}
+
+/// Walks over the characters of a score while keeping track of lines and columns
+struct Cursor<'a> {
+    chars: Peekable<CharIndices<'a>>,
+    len: usize,
+    line: usize,
+    column: usize,
+}
+
+impl<'a> Cursor<'a> {
+    fn new(score: &'a str) -> Self {
+        Cursor {
+            chars: score.char_indices().peekable(),
+            len: score.len(),
+            line: 1,
+            column: 1,
+        }
+    }
+
+    fn peek(&mut self) -> Option<char> {
+        self.chars.peek().map(|(_, c)| *c)
+    }
+
+    /// The character after the next character
+    fn peek_second(&self) -> Option<char> {
+        self.chars.clone().nth(1).map(|(_, c)| c)
+    }
+
+    /// The byte offset of the next character
+    fn offset(&mut self) -> usize {
+        self.chars.peek().map(|(i, _)| *i).unwrap_or(self.len)
+    }
+
+    /// A span starting at the next character
+    fn here(&mut self) -> Span {
+        let offset = self.offset();
+        Span {
+            line: self.line,
+            column: self.column,
+            start: offset,
+            end: offset,
+        }
+    }
+
+    /// Closes a span that was started with [Cursor::here]
+    fn close(&mut self, span: Span) -> Span {
+        Span {
+            end: self.offset(),
+            ..span
+        }
+    }
+
+    fn next(&mut self) -> Option<char> {
+        let (_, c) = self.chars.next()?;
+        if c == '\n' {
+            self.line += 1;
+            self.column = 1;
+        } else {
+            self.column += 1;
+        }
+        Some(c)
+    }
+
+    fn digits(&mut self) -> String {
+        let mut num = String::new();
+        while self.peek().is_some_and(|c| c.is_ascii_digit()) {
+            num.push(self.next().unwrap());
+        }
+        num
+    }
+}
+
+fn token_error(e: TokenError, span: Span) -> MetrumError {
+    MetrumError::TokenError(e, span)
+}
+
+/// Parses a string of digits, which is too large if it doesn't fit into a `u16`
+fn number(digits: &str, span: Span) -> Result<u16, MetrumError> {
+    digits
+        .parse::<u16>()
+        .map_err(|_| token_error(TokenError::NumberTooLarge, span))
 }
 
-pub fn scan(score: String) -> Result<Vec<Token>, MetrumError> {
-    let mut score = score.chars().peekable();
-    let mut tokens: Vec<Token> = Vec::new();
+pub fn scan(score: String) -> Result<Vec<Spanned>, MetrumError> {
+    let mut score = Cursor::new(&score);
+    let mut tokens: Vec<Spanned> = Vec::new();
 
     while score.peek().is_some() {
+        let span = score.here();
         let curr = score.next().unwrap();
-        match curr {
-            ' ' => {}
-            '\n' => {}
-            '\r' => {}
-            '.' => tokens.push(Token::Dot),
-            '=' => tokens.push(Token::Equal),
-            '|' => tokens.push(Token::Barline),
-            'w' => tokens.push(Token::Ratio(1, 1)),
-            'h' => tokens.push(Token::Ratio(1, 2)),
-            'q' => tokens.push(Token::Ratio(1, 4)),
-            'e' => tokens.push(Token::Ratio(1, 8)),
-            's' => tokens.push(Token::Ratio(1, 16)),
-            't' => tokens.push(Token::Ratio(1, 32)),
-            'x' => {
-                let mut num = String::new();
-                while score.peek().is_some() && score.peek().unwrap().is_ascii_digit() {
-                    num.push(score.next().unwrap());
+        let token = match curr {
+            ' ' | '\n' | '\r' => continue,
+            '.' => Token::Dot,
+            '=' => Token::Equal,
+            '|' => match score.peek() {
+                Some(':') => {
+                    score.next();
+                    Token::RepeatStart
                 }
-                if num.is_empty() {
-                    return Err(MetrumError::TokenError(TokenError::MissingRepetition('x')));
+                _ => Token::Barline,
+            },
+            ':' => {
+                if score.peek() != Some('|') {
+                    return Err(token_error(
+                        TokenError::InvalidCharacter(curr),
+                        score.close(span),
+                    ));
                 }
-                let parsed = num.parse::<u16>().unwrap();
-                if parsed <= 1 {
-                    return Err(MetrumError::TokenError(TokenError::NotEnoughRepeats));
+                score.next();
+                if score.peek() != Some(':') {
+                    Token::RepeatEnd
+                } else {
+                    // `:|:` ends one repeated passage and starts the next
+                    tokens.push(Spanned {
+                        token: Token::RepeatEnd,
+                        span: score.close(span),
+                    });
+                    score.next();
+                    Token::RepeatStart
                 }
-                tokens.push(Token::NoteRepeat(parsed));
             }
-            '%' => {
-                let mut num = String::new();
-                while score.peek().is_some() && score.peek().unwrap().is_ascii_digit() {
-                    num.push(score.next().unwrap());
+            'w' => Token::Ratio(1, 1),
+            'h' => Token::Ratio(1, 2),
+            'q' => Token::Ratio(1, 4),
+            'e' => Token::Ratio(1, 8),
+            's' => Token::Ratio(1, 16),
+            't' => Token::Ratio(1, 32),
+            'r' => Token::Rest,
+            '^' => Token::Accent(Accent::Downbeat),
+            '>' => Token::Accent(Accent::Secondary),
+            '\'' => Token::Accent(Accent::Ghost),
+            '_' => Token::Accent(Accent::Silent),
+            '(' => Token::LeftParen,
+            ')' => Token::RightParen,
+            '+' => Token::Plus,
+            '@' => Token::At,
+            '~' => {
+                let curve = match score.peek() {
+                    Some('~') => {
+                        score.next();
+                        Curve::Exponential
+                    }
+                    _ => Curve::Linear,
+                };
+                if score.peek() != Some('>') {
+                    return Err(token_error(TokenError::IncompleteRamp, score.close(span)));
                 }
+                score.next();
+                Token::Ramp(curve)
+            }
+            '[' => {
+                let mut name = String::new();
+                while score.peek().is_some_and(|c| c != ']' && c != '\n') {
+                    name.push(score.next().unwrap());
+                }
+                if score.next() != Some(']') {
+                    return Err(token_error(TokenError::UnclosedSection, score.close(span)));
+                }
+                let name = name.trim();
+                if name.is_empty() {
+                    return Err(token_error(TokenError::EmptySection, score.close(span)));
+                }
+                Token::Section(name.to_string())
+            }
+            '#' => {
+                let mut name = String::new();
+                while score
+                    .peek()
+                    .is_some_and(|c| c.is_ascii_alphabetic() || c == '-')
+                {
+                    name.push(score.next().unwrap());
+                }
+                match Directive::from_name(&name) {
+                    Some(directive) => Token::Directive(directive),
+                    None => {
+                        return Err(token_error(
+                            TokenError::UnknownDirective(name),
+                            score.close(span),
+                        ))
+                    }
+                }
+            }
+            '%' if matches!(score.peek(), Some('%' | '[')) => {
+                // `%%N` repeats the last two bars, `%[G]xN` the last G bars
+                let group = match score.next() {
+                    Some('%') => 2,
+                    _ => {
+                        let group_span = score.here();
+                        let group = score.digits();
+                        let group_span = score.close(group_span);
+                        if group.is_empty()
+                            || score.next() != Some(']')
+                            || score.next() != Some('x')
+                        {
+                            return Err(token_error(
+                                TokenError::IncompleteBarRepeat,
+                                score.close(span),
+                            ));
+                        }
+                        match number(&group, group_span)? {
+                            0 => return Err(token_error(TokenError::Zero, group_span)),
+                            group => group,
+                        }
+                    }
+                };
+                let num = score.digits();
+                let span = score.close(span);
                 if num.is_empty() {
-                    return Err(MetrumError::TokenError(TokenError::MissingRepetition('%')));
+                    return Err(token_error(TokenError::MissingRepetition(curr), span));
                 }
-                let parsed = num.parse::<u16>().unwrap();
+                match number(&num, span)? {
+                    times if times <= 1 => {
+                        return Err(token_error(TokenError::NotEnoughRepeats, span))
+                    }
+                    times => Token::BarRepeat(group, times),
+                }
+            }
+            'x' | '%' => {
+                let num = score.digits();
+                let span = score.close(span);
+                if num.is_empty() {
+                    return Err(token_error(TokenError::MissingRepetition(curr), span));
+                }
+                let parsed = number(&num, span)?;
                 if parsed <= 1 {
-                    return Err(MetrumError::TokenError(TokenError::NotEnoughRepeats));
+                    return Err(token_error(TokenError::NotEnoughRepeats, span));
+                }
+                match curr {
+                    'x' => Token::NoteRepeat(parsed),
+                    _ => Token::BarRepeat(1, parsed),
                 }
-                tokens.push(Token::BarRepeat(parsed));
             }
-            '/' => return Err(MetrumError::TokenError(TokenError::LeadingSlash)),
+            '/' => return Err(token_error(TokenError::LeadingSlash, score.close(span))),
             _ => {
                 if curr.is_ascii_digit() {
-                    let mut num = String::from(curr);
-                    while score.peek().is_some() && score.peek().unwrap().is_ascii_digit() {
-                        num.push(score.next().unwrap());
-                    }
-                    let parsed_num = num.parse::<u16>().unwrap();
-                    if parsed_num == 0 {
-                        return Err(MetrumError::TokenError(TokenError::Zero));
+                    let num = String::from(curr) + &score.digits();
+                    let num_span = score.close(span);
+                    let parsed_num = number(&num, num_span)?;
+                    // a decimal may start with 0, like 0.5, as long as it isn't 0 as a whole
+                    let is_decimal = score.peek() == Some('.')
+                        && score.peek_second().is_some_and(|c| c.is_ascii_digit());
+                    // `#count-in 0` turns off the count-in, like `--count-in 0`
+                    let after_count_in = tokens
+                        .last()
+                        .is_some_and(|t| t.token == Token::Directive(Directive::CountIn));
+                    if parsed_num == 0 && !is_decimal && !after_count_in {
+                        return Err(token_error(TokenError::Zero, num_span));
                     }
 
-                    if score.peek().is_some() && *score.peek().unwrap() == '/' {
+                    if score.peek() == Some('/') {
                         score.next();
-                        if score.peek().is_some() && score.peek().unwrap().is_ascii_digit() {
-                            let mut bottom = String::new();
-                            while score.peek().is_some() && score.peek().unwrap().is_ascii_digit() {
-                                bottom.push(score.next().unwrap());
-                            }
-                            let parsed_bottom = bottom.parse::<u16>().unwrap();
+                        if score.peek().is_some_and(|c| c.is_ascii_digit()) {
+                            let bottom_span = score.here();
+                            let bottom = score.digits();
+                            let parsed_bottom = number(&bottom, score.close(bottom_span))?;
                             if parsed_bottom == 0 {
-                                return Err(MetrumError::TokenError(TokenError::Zero));
+                                return Err(token_error(
+                                    TokenError::Zero,
+                                    score.close(bottom_span),
+                                ));
                             }
-                            tokens.push(Token::Ratio(parsed_num, parsed_bottom));
+                            Token::Ratio(parsed_num, parsed_bottom)
                         } else {
-                            return Err(MetrumError::TokenError(TokenError::IncompleteRatio));
+                            return Err(token_error(
+                                TokenError::IncompleteRatio,
+                                score.close(span),
+                            ));
+                        }
+                    } else if is_decimal {
+                        score.next();
+                        let fraction = score.digits();
+                        let parsed_fraction = fraction
+                            .parse::<u32>()
+                            .ok()
+                            .filter(|_| fraction.len() <= 9)
+                            .ok_or_else(|| {
+                                token_error(TokenError::NumberTooLarge, score.close(span))
+                            })?;
+                        if parsed_num == 0 && parsed_fraction == 0 {
+                            return Err(token_error(TokenError::Zero, score.close(span)));
                         }
+                        Token::Decimal(parsed_num, parsed_fraction, fraction.len() as u32)
                     } else {
-                        tokens.push(Token::Number(parsed_num));
+                        Token::Number(parsed_num)
                     }
                 } else {
-                    return Err(MetrumError::TokenError(TokenError::InvalidCharacter(curr)));
+                    return Err(token_error(
+                        TokenError::InvalidCharacter(curr),
+                        score.close(span),
+                    ));
                 }
             }
-        }
+        };
+        tokens.push(Spanned {
+            token,
+            span: score.close(span),
+        });
     }
     Ok(tokens)
 }
@@ -101,9 +390,13 @@ pub fn scan(score: String) -> Result<Vec<Token>, MetrumError> {
 mod tests {
     use super::*;
 
+    fn tokens(s: &str) -> Result<Vec<Token>, MetrumError> {
+        scan(s.to_string()).map(|toks| toks.into_iter().map(|t| t.token).collect())
+    }
+
     #[test]
     fn single_tokens() {
-        let data = vec![
+        let data = [
             (".", Token::Dot),
             ("=", Token::Equal),
             ("|", Token::Barline),
@@ -113,9 +406,29 @@ mod tests {
             ("e", Token::Ratio(1, 8)),
             ("s", Token::Ratio(1, 16)),
             ("t", Token::Ratio(1, 32)),
+            ("r", Token::Rest),
+            ("^", Token::Accent(Accent::Downbeat)),
+            (">", Token::Accent(Accent::Secondary)),
+            ("'", Token::Accent(Accent::Ghost)),
+            ("_", Token::Accent(Accent::Silent)),
+            ("(", Token::LeftParen),
+            (")", Token::RightParen),
+            ("+", Token::Plus),
+            ("#time", Token::Directive(Directive::Time)),
+            ("#count-in", Token::Directive(Directive::CountIn)),
+            ("#subdivide", Token::Directive(Directive::Subdivide)),
+            ("~>", Token::Ramp(Curve::Linear)),
+            ("~~>", Token::Ramp(Curve::Exponential)),
+            ("@", Token::At),
+            ("[A]", Token::Section("A".to_string())),
+            ("[ Coda 2 ]", Token::Section("Coda 2".to_string())),
+            ("[12]", Token::Section("12".to_string())),
+            ("[ 2 ]", Token::Section("2".to_string())),
+            ("|:", Token::RepeatStart),
+            (":|", Token::RepeatEnd),
         ];
         for (s, tok) in data.iter() {
-            let output = scan(s.to_string());
+            let output = tokens(s);
             assert!(output.is_ok());
             assert_eq!(output.clone().unwrap().len(), 1);
             assert_eq!(output.clone().unwrap()[0], *tok);
@@ -124,57 +437,91 @@ mod tests {
 
     #[test]
     fn numbers() {
-        let data = vec![
+        let data = [
             ("123", 1, vec![Token::Number(123)]),
+            ("92.5", 1, vec![Token::Decimal(92, 5, 1)]),
+            ("60.05", 1, vec![Token::Decimal(60, 5, 2)]),
+            ("0.5", 1, vec![Token::Decimal(0, 5, 1)]),
+            ("0.05", 1, vec![Token::Decimal(0, 5, 2)]),
+            ("7.", 2, vec![Token::Number(7), Token::Dot]),
+            (
+                "1/4.5",
+                3,
+                vec![Token::Ratio(1, 4), Token::Dot, Token::Number(5)],
+            ),
             (
                 "1 2 3",
                 3,
                 vec![Token::Number(1), Token::Number(2), Token::Number(3)],
             ),
         ];
-        for (s, l, tokens) in data.iter() {
-            let output = scan(s.to_string());
+        for (s, l, toks) in data.iter() {
+            let output = tokens(s);
             assert!(output.is_ok(), "{}", output.unwrap_err());
             assert_eq!(output.clone().unwrap().len(), *l);
-            assert_eq!(output.clone().unwrap(), *tokens);
+            assert_eq!(output.clone().unwrap(), *toks);
         }
     }
 
     #[test]
     fn ratios() {
-        let data = vec![
+        let data = [
             ("1/2", 1, vec![Token::Ratio(1, 2)]),
             ("1/2 1/2", 2, vec![Token::Ratio(1, 2), Token::Ratio(1, 2)]),
         ];
-        for (s, l, tokens) in data.iter() {
-            let output = scan(s.to_string());
+        for (s, l, toks) in data.iter() {
+            let output = tokens(s);
             assert!(output.is_ok(), "{}", output.unwrap_err());
             assert_eq!(output.clone().unwrap().len(), *l);
-            assert_eq!(output.clone().unwrap(), *tokens);
+            assert_eq!(output.clone().unwrap(), *toks);
         }
     }
 
     #[test]
     fn repeats() {
-        let data = vec![
+        let data = [
             ("x2", 1, vec![Token::NoteRepeat(2)]),
             ("x2 x5", 2, vec![Token::NoteRepeat(2), Token::NoteRepeat(5)]),
-            ("%2", 1, vec![Token::BarRepeat(2)]),
-            ("x2 %5", 2, vec![Token::NoteRepeat(2), Token::BarRepeat(5)]),
+            ("%2", 1, vec![Token::BarRepeat(1, 2)]),
+            (
+                "x2 %5",
+                2,
+                vec![Token::NoteRepeat(2), Token::BarRepeat(1, 5)],
+            ),
+            ("%%2", 1, vec![Token::BarRepeat(2, 2)]),
+            (
+                "%[4]x3 %[1]x2",
+                2,
+                vec![Token::BarRepeat(4, 3), Token::BarRepeat(1, 2)],
+            ),
+            (
+                "|: :|: :| |",
+                5,
+                vec![
+                    Token::RepeatStart,
+                    Token::RepeatEnd,
+                    Token::RepeatStart,
+                    Token::RepeatEnd,
+                    Token::Barline,
+                ],
+            ),
+            ("||:", 2, vec![Token::Barline, Token::RepeatStart]),
         ];
 
-        for (s, l, tokens) in data.iter() {
-            let output = scan(s.to_string());
+        for (s, l, toks) in data.iter() {
+            let output = tokens(s);
             assert!(output.is_ok(), "{}", output.unwrap_err());
             assert_eq!(output.clone().unwrap().len(), *l);
-            assert_eq!(output.clone().unwrap(), *tokens);
+            assert_eq!(output.clone().unwrap(), *toks);
         }
     }
 
     #[test]
     fn invalid_scores() {
-        let data = vec![
+        let data = [
             "i", "ul", "/", "/8", "1/2/4", "1/ 2", "1 /2", "x 1", "% 1", "|q|%1", "qx1", "qx0",
+            "#", "#tempo", "~", "~~", "~ >", "[", "[A", "[A\n]", "[]", "[ ]", ":", ": |", "%%",
+            "%%1", "%[", "%[4", "%[4]", "%[4]3", "%[]x3", "%[0]x3", "%[4]x1", "%[4] x3",
         ];
         for s in data.iter() {
             let output = scan(s.to_string());
@@ -182,6 +529,56 @@ mod tests {
         }
     }
 
+    #[test]
+    fn spans() {
+        let output = scan("| q\n  1/16 x3 |".to_string()).unwrap();
+        let spans: Vec<(usize, usize, usize, usize)> = output
+            .iter()
+            .map(|t| (t.span.line, t.span.column, t.span.start, t.span.end))
+            .collect();
+        assert_eq!(
+            spans,
+            vec![
+                (1, 1, 0, 1),
+                (1, 3, 2, 3),
+                (2, 3, 6, 10),
+                (2, 8, 11, 13),
+                (2, 11, 14, 15)
+            ]
+        );
+    }
+
+    #[test]
+    fn error_spans() {
+        let data = [
+            ("| q i", TokenError::InvalidCharacter('i'), 1, 5),
+            ("| q |\n| qx1 |", TokenError::NotEnoughRepeats, 2, 4),
+            ("|\n q 1/", TokenError::IncompleteRatio, 2, 4),
+            ("| 1/0", TokenError::Zero, 1, 5),
+            ("| 0.0", TokenError::Zero, 1, 3),
+            ("| 0.", TokenError::Zero, 1, 3),
+            ("#subdivide 0", TokenError::Zero, 1, 12),
+            ("| q = 65536", TokenError::NumberTooLarge, 1, 7),
+            ("| 1/99999", TokenError::NumberTooLarge, 1, 5),
+            ("| q = 1.0000000001", TokenError::NumberTooLarge, 1, 7),
+            ("| q |%70000", TokenError::NumberTooLarge, 1, 6),
+            ("| q |%[4]", TokenError::IncompleteBarRepeat, 1, 6),
+            ("| q |%[0]x2", TokenError::Zero, 1, 8),
+            ("| q |\n[A | q |", TokenError::UnclosedSection, 2, 1),
+            ("| q | [] | q |", TokenError::EmptySection, 1, 7),
+        ];
+        for (s, error, line, column) in data.iter() {
+            let output = scan(s.to_string());
+            match output {
+                Err(MetrumError::TokenError(e, span)) => {
+                    assert_eq!(e, *error);
+                    assert_eq!((span.line, span.column), (*line, *column), "{s}");
+                }
+                _ => panic!("expected a token error for {s}"),
+            }
+        }
+    }
+
     extern crate test_generator;
     use test_generator::test_resources;
 
@@ -1,182 +1,1246 @@
+use std::{iter::Peekable, slice::Iter};
+
 use crate::{
-    error::{MetrumError, ParseError},
-    scanner::Token,
+    error::{ConversionError, MetrumError, ParseError, TempoError},
+    scanner::{Directive, Span, Spanned, Token},
+    units::Rational,
 };
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 /// A tempo represents how fast music is going and is often represented in terms of *how many this
 /// kind of beats per minute*. For example 140 quarter notes per minute.
 pub struct Tempo {
-    /// A tuple representing the kind of beat as a ratio. For example, the tuple `(1,4)` would
-    /// represent a quarter (1/4) note.
-    pub beat: (u16, u16),
+    /// The kind of beat as a fraction of a whole note. For example, `1/4` would represent a
+    /// quarter note and `3/8` a dotted quarter note.
+    pub beat: Rational,
     /// The number of [Tempo::beat] per minute
-    pub num_beats: u16,
+    pub num_beats: Rational,
 }
 
 impl Tempo {
-    pub fn new(beat: (u16, u16), num_beats: u16) -> Self {
+    pub fn new(beat: Rational, num_beats: Rational) -> Self {
         Tempo { beat, num_beats }
     }
 }
 
+impl Default for Tempo {
+    /// The tempo of a score that doesn't set one, 120 quarter notes per minute
+    fn default() -> Self {
+        Tempo::new(Rational::new(1, 4), Rational::from(120))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+/// The shape of a gradual tempo change
+pub enum Curve {
+    /// The tempo changes by the same amount of beats per minute over every note, written `~>`
+    Linear,
+    /// The tempo changes by the same factor over every note, written `~~>`
+    Exponential,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+/// A gradual tempo change (accelerando or ritardando) that starts at a [TempoMark] and reaches
+/// [Ramp::target] beats per minute at [Ramp::end]
+pub struct Ramp {
+    /// The number of beats per minute at the end of the ramp, counted in the beat of the
+    /// [TempoMark] the ramp starts at
+    pub target: Rational,
+    pub curve: Curve,
+    /// The position in whole notes from the start of the score where the target is reached
+    pub end: Rational,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+/// A tempo that takes effect at a position in the score and lasts until the next mark
+pub struct TempoMark {
+    /// The position in whole notes from the start of the score
+    pub position: Rational,
+    pub tempo: Tempo,
+    pub ramp: Option<Ramp>,
+    /// Whether the tempo follows from the tempo before instead of being written out, like after
+    /// `q = q.`
+    pub relative: bool,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+/// What happens at the start of a [Duration]
+pub enum EventKind {
+    /// A click is played at the start of the duration
+    Note,
+    /// The duration passes in silence
+    Rest,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+/// How strongly a note is accented. Unless written explicitly, the first note of a bar is a
+/// [Accent::Downbeat] and every other note is [Accent::Unaccented].
+pub enum Accent {
+    /// The strongest accent, written as `^q`
+    Downbeat,
+    /// A secondary beat, written as `>q`
+    Secondary,
+    /// A normal, unaccented beat
+    Unaccented,
+    /// A quiet ghost note, written as `q'`
+    Ghost,
+    /// A note that is counted but not heard, written as `q_`
+    Silent,
+}
+
+impl Accent {
+    /// Whether the accent is written before the note it belongs to
+    pub fn is_prefix(&self) -> bool {
+        matches!(self, Accent::Downbeat | Accent::Secondary)
+    }
+}
+
+/// Returns the accent a note gets when none is written, based on where in the bar it starts
+fn default_accent(position: Rational, meter: Option<&Meter>) -> Accent {
+    if position.is_zero() {
+        return Accent::Downbeat;
+    }
+    match meter {
+        Some(meter) if meter.group_starts().contains(&position) => Accent::Secondary,
+        _ => Accent::Unaccented,
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+/// A time signature such as `4/4` or `7/8 (2+2+3)`. The groups divide the bar into beats of
+/// [Meter::bottom] and decide where secondary accents fall.
+pub struct Meter {
+    pub top: u16,
+    pub bottom: u16,
+    pub groups: Vec<u16>,
+}
+
+impl Meter {
+    /// Creates a meter with the conventional grouping: compound meters are grouped in threes,
+    /// even meters in twos and everything else is a single group.
+    pub fn new(top: u16, bottom: u16) -> Self {
+        let group = match top {
+            t if t > 3 && t % 3 == 0 => 3,
+            t if t > 2 && t % 2 == 0 => 2,
+            t => t,
+        };
+        Meter {
+            top,
+            bottom,
+            groups: vec![group; (top / group) as usize],
+        }
+    }
+
+    /// Creates the time signature a bar of the given length in whole notes would have, counted in
+    /// quarter notes or shorter, e.g. `4/4` for a whole note or `5/8` for five eighth notes
+    pub fn from_length(length: Rational) -> Self {
+        let (mut top, mut bottom) = (length.numer(), length.denom());
+        while bottom < 4 {
+            top *= 2;
+            bottom *= 2;
+        }
+        let saturate = |n: u64| u16::try_from(n).unwrap_or(u16::MAX);
+        Meter::new(saturate(top), saturate(bottom))
+    }
+
+    /// The length of a bar in this meter in whole notes
+    pub fn length(&self) -> Rational {
+        Rational::new(self.top as u64, self.bottom as u64)
+    }
+
+    /// Positions within the bar, in whole notes, where each group after the first one starts
+    pub fn group_starts(&self) -> Vec<Rational> {
+        let mut position = 0;
+        let mut starts = Vec::new();
+        for group in self.groups.iter().take(self.groups.len().saturating_sub(1)) {
+            position += *group as u64;
+            starts.push(Rational::new(position, self.bottom as u64));
+        }
+        starts
+    }
+}
+
+impl std::fmt::Display for Meter {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let groups: Vec<String> = self.groups.iter().map(|g| g.to_string()).collect();
+        write!(f, "{}/{} ({})", self.top, self.bottom, groups.join("+"))
+    }
+}
+
 #[derive(Clone, Debug)]
-/// A [Duration] struct contains how long a note is in *milliseconds* and whether the beat is
-/// strong or weak.
+/// A [Duration] struct contains exactly how long a note is in *milliseconds* and in whole notes,
+/// how strongly it is accented and whether it is a note or a rest.
 pub struct Duration {
-    pub ms: f32,
-    pub strong: bool,
+    pub ms: Rational,
+    pub value: Rational,
+    pub accent: Accent,
+    pub kind: EventKind,
 }
 
 impl Duration {
-    /// creates a weak [Duration] from any duration
-    pub fn to_weak(&self) -> Self {
+    /// creates a copy of the duration with a different accent
+    pub fn with_accent(&self, accent: Accent) -> Self {
         Duration {
-            ms: self.ms,
-            strong: false,
+            accent,
+            ..self.clone()
         }
     }
+
+    /// Whether this duration is a rest
+    pub fn is_rest(&self) -> bool {
+        self.kind == EventKind::Rest
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+/// What a click of the click track is for
+pub enum ClickKind {
+    /// A beat of the count-in with its accent
+    CountIn(Accent),
+    /// A note of the score with its accent
+    Note(Accent),
+    /// A subdivision of the beat
+    Subdivision,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+/// A click of the click track. Its position in whole notes and its time in *milliseconds* are
+/// both measured from the start of the count-in.
+pub struct Click {
+    pub position: Rational,
+    pub ms: Rational,
+    pub kind: ClickKind,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+/// Where a bar of the click track starts and the tempo and the time signature it starts in
+pub struct BarStart {
+    /// The number of the bar counted from 1, or `None` for the bars of the count-in
+    pub number: Option<usize>,
+    /// The start of the bar in whole notes from the start of the count-in
+    pub position: Rational,
+    /// The start of the bar in *milliseconds* from the start of the count-in
+    pub ms: Rational,
+    pub tempo: Tempo,
+    /// The time signature of the bar as it was declared, or as derived from its length
+    pub meter: Meter,
+    /// The name of the section that starts with the bar, if it has a rehearsal mark
+    pub section: Option<String>,
 }
 
 #[derive(Debug, Clone)]
-/// A struct containing a vector of durations
+/// A struct containing a vector of durations, the time signature the bar was written in, if one
+/// was declared, how many subdivision clicks to play per beat, if any, and the name of the section
+/// that starts with the bar, if it has a rehearsal mark
 pub struct Bar {
     pub durations: Vec<Duration>,
+    pub meter: Option<Meter>,
+    pub subdivision: Option<u16>,
+    pub section: Option<String>,
+}
+
+impl Default for Bar {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Bar {
     pub fn new() -> Self {
         Bar {
             durations: Vec::new(),
+            meter: None,
+            subdivision: None,
+            section: None,
         }
     }
+
+    /// The length of the bar in whole notes
+    pub fn length(&self) -> Rational {
+        self.durations
+            .iter()
+            .fold(Rational::zero(), |acc, d| acc + d.value)
+    }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 /// A score contains a vector of bars, which in turn contains a vector of durations. A score
 /// represents rhythm as a collection of durations, which will later be used to write the click
-/// track.
+/// track. The tempo map holds every tempo change in order, starting with the tempo at the very
+/// beginning of the score.
 pub struct Score {
     pub bars: Vec<Bar>,
+    pub tempo_map: Vec<TempoMark>,
+    /// The number of bars counted in before the first bar, see [Score::count_in_bars]
+    pub count_in: u16,
 }
 
 impl Score {
     /// Takes in a list of [tokens][crate::scanner::Token] that has been parsed by
     /// the [scanner][crate::scanner] and returns a [Score] wrapped in a result.
-    pub fn new(tokens: Vec<Token>) -> Result<Self, MetrumError> {
+    pub fn new(tokens: Vec<Spanned>) -> Result<Self, MetrumError> {
+        let last_span = tokens.last().map(|t| t.span).unwrap_or_default();
         let mut tokens = tokens.iter().peekable();
         let mut bars: Vec<Bar> = Vec::new();
         let mut bar = Bar::new();
-        let mut tempo = Tempo::new((1, 4), 120);
+        let mut tempo = Tempo::default();
+        let mut tempo_map = vec![TempoMark {
+            position: Rational::zero(),
+            tempo,
+            ramp: None,
+            relative: false,
+        }];
+        // ramps that end after a number of bars, as (index in tempo map, bar index, bar count)
+        let mut ramp_bars: Vec<(usize, usize, u16)> = Vec::new();
+        // the length of all finished bars in whole notes
+        let mut length = Rational::zero();
+        let mut meter: Option<Meter> = None;
+        let mut count_in = 0;
+        let mut subdivision: Option<u16> = None;
+        // the rehearsal mark of the next bar
+        let mut section: Option<(String, Span)> = None;
+        // the index of the first bar after an open `|:` and of the first bar of its first ending
+        let mut repeat_start: Option<(usize, Span)> = None;
+        let mut first_ending: Option<usize> = None;
+        // whether the last `:|` had a first ending, so that a second ending may follow
+        let mut second_ending = false;
+        // whether no bar has started since the last `:|`, which makes `[1]` and `[2]` endings
+        let mut after_repeat = false;
         let mut history: Vec<Token> = Vec::new();
-        while tokens.peek().is_some() {
-            let curr = tokens.next().unwrap();
-            history.push(*curr);
+        // an accent written in front of the next note
+        let mut pending_accent: Option<(Accent, Span)> = None;
+        // the accent explicitly written on the last note, carried over by note repeats
+        let mut written_accent: Option<Accent> = None;
+        // the accents explicitly written on the notes of the current bar, kept by repeated groups
+        let mut written_accents: Vec<Option<Accent>> = Vec::new();
+        // the index of the first note of every open group in the current bar
+        let mut groups: Vec<(usize, Span)> = Vec::new();
+        let parse_error = |e: ParseError, span: Span| MetrumError::ParseError(e, span);
+        let conversion_error =
+            |e: ConversionError, span: Span| MetrumError::ConversionError(e, span);
+        while let Some(Spanned { token: curr, span }) = tokens.next() {
+            history.push(curr.clone());
             match curr {
-                Token::Barline => {
+                Token::Barline | Token::RepeatStart | Token::RepeatEnd => {
+                    if let Some((_, span)) = groups.first() {
+                        return Err(parse_error(ParseError::UnclosedGroup, *span));
+                    }
                     if !bar.durations.is_empty() {
+                        if let Some(meter) = &meter {
+                            if bar.length() != meter.length() {
+                                let error = ParseError::BarLength {
+                                    bar: bars.len() + 1,
+                                    expected: meter.length(),
+                                    actual: bar.length(),
+                                };
+                                return Err(parse_error(error, *span));
+                            }
+                        }
+                        bar.meter = meter.clone();
+                        bar.subdivision = subdivision;
+                        bar.section = section.take().map(|(name, _)| name);
+                        length += bar.length();
                         bars.push(bar);
                         bar = Bar::new();
+                        written_accents.clear();
+                        second_ending = false;
+                        after_repeat = false;
+                        // a ramp written after the last note of a bar counts its bars from the next
+                        for (index, bar_index, _) in ramp_bars.iter_mut() {
+                            if *bar_index < bars.len() && tempo_map[*index].position == length {
+                                *bar_index = bars.len();
+                            }
+                        }
+                    }
+                    match curr {
+                        Token::RepeatStart => {
+                            if repeat_start.is_some() {
+                                return Err(parse_error(ParseError::NestedRepeat, *span));
+                            }
+                            repeat_start = Some((bars.len(), *span));
+                        }
+                        Token::RepeatEnd => {
+                            let Some((first_bar, start_span)) = repeat_start.take() else {
+                                return Err(parse_error(ParseError::UnopenedRepeat, *span));
+                            };
+                            if first_bar == bars.len() {
+                                return Err(parse_error(ParseError::EmptyRepeat, start_span));
+                            }
+                            let end_bar = first_ending.take().unwrap_or(bars.len());
+                            second_ending = end_bar < bars.len();
+                            after_repeat = true;
+                            let start = bars[..first_bar]
+                                .iter()
+                                .fold(Rational::zero(), |acc, bar| acc + bar.length());
+                            let repeated = copy_bars(&bars[first_bar..end_bar]);
+                            let end = repeated.iter().fold(start, |acc, bar| acc + bar.length());
+                            repeat_tempo_marks(
+                                &mut tempo_map,
+                                &mut ramp_bars,
+                                (start, end, first_bar, end_bar),
+                                (length, bars.len()),
+                            );
+                            length += end - start;
+                            bars.extend(repeated);
+                        }
+                        _ => {}
+                    }
+                }
+                // inside a repeat or right after one, `[1]` and `[2]` are endings instead of
+                // rehearsal marks
+                Token::Section(name)
+                    if (repeat_start.is_some() || after_repeat)
+                        && matches!(name.as_str(), "1" | "2") =>
+                {
+                    if name == "1" {
+                        if !bar.durations.is_empty()
+                            || repeat_start.is_none()
+                            || first_ending.is_some()
+                        {
+                            return Err(parse_error(ParseError::FirstEnding, *span));
+                        }
+                        first_ending = Some(bars.len());
+                    } else {
+                        if !bar.durations.is_empty() || !second_ending {
+                            return Err(parse_error(ParseError::SecondEnding, *span));
+                        }
+                        second_ending = false;
                     }
                 }
-                Token::Ratio(top, bottom) => {
+                Token::Ratio(..) => {
                     let mut num_dots = 0;
-                    while tokens.peek().is_some() && **tokens.peek().unwrap() == Token::Dot {
+                    let mut span = *span;
+                    while let Some(dot) = tokens.next_if(|t| t.token == Token::Dot) {
                         num_dots += 1;
-                        tokens.next();
+                        span = span.to(dot.span);
                     }
-                    if tokens.peek().is_some() && **tokens.peek().unwrap() == Token::Equal {
-                        tokens.next();
-                        let number = tokens.next();
-                        match number {
-                            Some(number) => match number {
-                                Token::Number(n) => {
-                                    tempo = Tempo::new((*top, *bottom), *n);
-                                }
-                                Token::Ratio(top2, bottom2) => {
-                                    let mut dots = 0;
-                                    while tokens.peek().is_some()
-                                        && **tokens.peek().unwrap() == Token::Dot
-                                    {
-                                        dots += 1;
-                                        tokens.next();
+                    let prefix = pending_accent.take();
+                    let tempo_follows = tokens
+                        .peek()
+                        .is_some_and(|t| matches!(t.token, Token::Equal | Token::Ramp(_)));
+                    if tempo_follows {
+                        if prefix.is_some() {
+                            return Err(parse_error(ParseError::Accent, span));
+                        }
+                        let beat = curr
+                            .as_value(num_dots)
+                            .map_err(|e| conversion_error(e, span))?;
+                        let mut relative = false;
+                        let mut absolute = false;
+                        if let Some(equal) = tokens.next_if(|t| t.token == Token::Equal) {
+                            let bpm = parse_beats_per_minute(&mut tokens, |span| {
+                                parse_error(ParseError::MissingTempoSpecifier, span)
+                            })?;
+                            if let Some(bpm) = bpm {
+                                tempo = Tempo::new(beat, bpm);
+                                absolute = true;
+                            } else {
+                                match tokens.next() {
+                                    Some(Spanned {
+                                        token: Token::Ratio(top2, bottom2),
+                                        span: span2,
+                                    }) => {
+                                        let mut dots = 0;
+                                        let mut span2 = *span2;
+                                        while let Some(dot) =
+                                            tokens.next_if(|t| t.token == Token::Dot)
+                                        {
+                                            dots += 1;
+                                            span2 = span2.to(dot.span);
+                                        }
+                                        let to = Token::Ratio(*top2, *bottom2)
+                                            .as_value(dots)
+                                            .map_err(|e| conversion_error(e, span2))?;
+                                        if to > Rational::from(4) {
+                                            return Err(parse_error(ParseError::TempoRatio, span2));
+                                        }
+                                        tempo = tempo.relative_to(beat, to);
+                                        relative = true;
+                                    }
+                                    Some(Spanned { span: span2, .. }) => {
+                                        return Err(parse_error(
+                                            ParseError::MissingTempoSpecifier,
+                                            *span2,
+                                        ));
+                                    }
+                                    None => {
+                                        return Err(parse_error(
+                                            ParseError::MissingTempoSpecifier,
+                                            equal.span,
+                                        ));
                                     }
-                                    tempo = tempo.relative_to(
-                                        &Token::Ratio(*top, *bottom).apply_dots(num_dots)?,
-                                        &Token::Ratio(*top2, *bottom2).apply_dots(dots)?,
-                                    )?;
-                                }
-                                _ => {
-                                    return Err(MetrumError::ParseError(
-                                        ParseError::MissingTempoSpecifier,
-                                    ));
                                 }
-                            },
-                            None => {
-                                return Err(MetrumError::ParseError(
-                                    ParseError::MissingTempoSpecifier,
-                                ));
                             }
                         }
-                    } else {
-                        let duration = curr.as_duration_ms(&tempo, num_dots);
-                        match duration {
-                            Ok(d) => bar.durations.push(Duration {
-                                ms: d,
-                                strong: bar.durations.is_empty(),
-                            }),
-                            Err(e) => {
-                                return Err(MetrumError::ConversionError(e));
+                        let mut ramp = None;
+                        let mut num_bars = None;
+                        if let Some(arrow) = tokens.next_if(|t| matches!(t.token, Token::Ramp(_))) {
+                            let Token::Ramp(curve) = arrow.token else {
+                                unreachable!()
+                            };
+                            if relative {
+                                return Err(parse_error(ParseError::Ramp, arrow.span));
                             }
+                            let target;
+                            (target, num_bars) = parse_ramp(&mut tokens, arrow.span)?;
+                            // express the tempo the ramp starts from in the beat of the ramp
+                            tempo = Tempo::new(beat, tempo.wholes_per_min() / beat);
+                            ramp = Some(Ramp {
+                                target,
+                                curve,
+                                end: Rational::zero(),
+                            });
+                        }
+                        let position = length + bar.length();
+                        let mark = TempoMark {
+                            position,
+                            tempo,
+                            ramp,
+                            relative: !absolute,
+                        };
+                        if tempo_map.last().unwrap().position == position {
+                            // a later tempo at the same position replaces the earlier one
+                            tempo_map.pop();
+                            ramp_bars.retain(|(index, ..)| *index != tempo_map.len());
+                        }
+                        if let Some(n) = num_bars {
+                            ramp_bars.push((tempo_map.len(), bars.len(), n));
+                        }
+                        tempo_map.push(mark);
+                        if let Some(ramp) = ramp {
+                            // relative tempo changes after a ramp are relative to its target
+                            tempo = Tempo::new(beat, ramp.target);
                         }
+                    } else {
+                        let kind = match tokens.next_if(|t| t.token == Token::Rest) {
+                            Some(_) => {
+                                // rests can't be accented, neither before nor after
+                                if let Some((_, accent_span)) = prefix {
+                                    return Err(parse_error(ParseError::Accent, accent_span));
+                                }
+                                while tokens.next_if(|t| t.token == Token::Dot).is_some() {
+                                    num_dots += 1;
+                                }
+                                EventKind::Rest
+                            }
+                            None => EventKind::Note,
+                        };
+                        let suffix = match kind {
+                            EventKind::Note => tokens
+                                .next_if(|t| matches!(t.token, Token::Accent(a) if !a.is_prefix()))
+                                .map(|t| match t.token {
+                                    Token::Accent(a) => a,
+                                    _ => unreachable!(),
+                                }),
+                            EventKind::Rest => None,
+                        };
+                        written_accent = suffix.or(prefix.map(|(accent, _)| accent));
+                        written_accents.push(written_accent);
+                        let value = curr
+                            .as_value(num_dots)
+                            .map_err(|e| conversion_error(e, span))?;
+                        let accent = default_accent(bar.length(), meter.as_ref());
+                        bar.durations.push(Duration {
+                            ms: Rational::zero(),
+                            value,
+                            accent: written_accent.unwrap_or(accent),
+                            kind,
+                        });
                     }
                 }
                 Token::NoteRepeat(n) => {
-                    if bar.durations.len() == 0 {
-                        return Err(MetrumError::ParseError(ParseError::NothingToRepeat));
+                    if bar.durations.is_empty() {
+                        return Err(parse_error(ParseError::NothingToRepeat, *span));
                     }
                     for _ in 0..(n - 1) {
-                        bar.durations.push(bar.durations.last().unwrap().to_weak());
+                        let accent = written_accent
+                            .unwrap_or_else(|| default_accent(bar.length(), meter.as_ref()));
+                        bar.durations
+                            .push(bar.durations.last().unwrap().with_accent(accent));
+                        written_accents.push(written_accent);
                     }
                 }
-                Token::BarRepeat(n) => {
-                    let prev = history.get(history.len() - 2);
+                Token::LeftParen => groups.push((bar.durations.len(), *span)),
+                Token::RightParen => {
+                    let repeat = tokens.next_if(|t| matches!(t.token, Token::NoteRepeat(_)));
+                    let (Some((first, _)), Some(repeat)) = (groups.pop(), repeat) else {
+                        return Err(parse_error(ParseError::GroupRepeat, *span));
+                    };
+                    let Token::NoteRepeat(n) = repeat.token else {
+                        unreachable!()
+                    };
+                    let group = bar.durations.len() - first;
+                    if group == 0 {
+                        return Err(parse_error(
+                            ParseError::NothingToRepeat,
+                            span.to(repeat.span),
+                        ));
+                    }
+                    for _ in 0..(n - 1) {
+                        for i in first..first + group {
+                            // notes without a written accent are accented for where they land
+                            let accent = written_accents[i]
+                                .unwrap_or_else(|| default_accent(bar.length(), meter.as_ref()));
+                            bar.durations.push(bar.durations[i].with_accent(accent));
+                            written_accents.push(written_accents[i]);
+                        }
+                    }
+                    written_accent = *written_accents.last().unwrap();
+                }
+                Token::BarRepeat(group, n) => {
+                    let prev = history.len().checked_sub(2).and_then(|i| history.get(i));
                     match prev {
-                        Some(tok) => match tok {
-                            Token::Barline => {
-                                if bars.len() == 0 {
-                                    return Err(MetrumError::ParseError(
-                                        ParseError::NothingToRepeat,
-                                    ));
-                                }
-                                for _ in 0..(n - 1) {
-                                    bars.push(bars.last().unwrap().clone())
-                                }
+                        Some(Token::Barline) => {
+                            let group = *group as usize;
+                            if bars.len() < group {
+                                return Err(parse_error(ParseError::NothingToRepeat, *span));
+                            }
+                            let first_bar = bars.len() - group;
+                            let repeated = copy_bars(&bars[first_bar..]);
+                            let repeated_length = repeated
+                                .iter()
+                                .fold(Rational::zero(), |acc, bar| acc + bar.length());
+                            let range = (length - repeated_length, length, first_bar, bars.len());
+                            for _ in 0..(n - 1) {
+                                repeat_tempo_marks(
+                                    &mut tempo_map,
+                                    &mut ramp_bars,
+                                    range,
+                                    (length, bars.len()),
+                                );
+                                length += repeated_length;
+                                bars.extend(repeated.iter().cloned());
                             }
-                            _ => return Err(MetrumError::ParseError(ParseError::BarRepeat)),
-                        },
-                        None => return Err(MetrumError::ParseError(ParseError::NothingToRepeat)),
+                        }
+                        Some(_) => return Err(parse_error(ParseError::BarRepeat, *span)),
+                        None => return Err(parse_error(ParseError::NothingToRepeat, *span)),
+                    }
+                }
+                Token::Number(_) | Token::Decimal(..) => {
+                    return Err(parse_error(ParseError::Number, *span))
+                }
+                Token::Equal => return Err(parse_error(ParseError::Equal, *span)),
+                Token::Dot => return Err(parse_error(ParseError::Dot, *span)),
+                Token::Rest => return Err(parse_error(ParseError::Rest, *span)),
+                Token::Ramp(_) | Token::At => return Err(parse_error(ParseError::Ramp, *span)),
+                Token::Directive(Directive::Time) => {
+                    if !bar.durations.is_empty() {
+                        return Err(parse_error(ParseError::TimeSignaturePlacement, *span));
+                    }
+                    meter = Some(parse_meter(&mut tokens, *span)?);
+                }
+                Token::Directive(Directive::CountIn) => {
+                    if !bars.is_empty() || !bar.durations.is_empty() {
+                        return Err(parse_error(ParseError::CountIn, *span));
+                    }
+                    match tokens.next() {
+                        Some(Spanned {
+                            token: Token::Number(n),
+                            ..
+                        }) => count_in = *n,
+                        Some(t) => return Err(parse_error(ParseError::CountIn, t.span)),
+                        None => return Err(parse_error(ParseError::CountIn, *span)),
+                    }
+                }
+                Token::Directive(Directive::Subdivide) => {
+                    if !bar.durations.is_empty() {
+                        return Err(parse_error(ParseError::SubdividePlacement, *span));
+                    }
+                    subdivision = match tokens.next_if(|t| matches!(t.token, Token::Number(_))) {
+                        Some(Spanned {
+                            token: Token::Number(n),
+                            ..
+                        }) => Some(*n),
+                        _ => None,
+                    };
+                }
+                Token::Plus => return Err(parse_error(ParseError::Grouping, *span)),
+                Token::Section(name) => {
+                    if !bar.durations.is_empty() {
+                        return Err(parse_error(ParseError::SectionPlacement, *span));
+                    }
+                    section = Some((name.clone(), *span));
+                }
+                Token::Accent(accent) => {
+                    let before_note = tokens
+                        .peek()
+                        .is_some_and(|t| matches!(t.token, Token::Ratio(..)));
+                    if !accent.is_prefix() || !before_note {
+                        return Err(parse_error(ParseError::Accent, *span));
                     }
+                    pending_accent = Some((*accent, *span));
                 }
-                Token::Number(_) => return Err(MetrumError::ParseError(ParseError::Number)),
-                Token::Equal => return Err(MetrumError::ParseError(ParseError::Equal)),
-                Token::Dot => return Err(MetrumError::ParseError(ParseError::Dot)),
             }
         }
 
         if !bar.durations.is_empty() {
-            return Err(MetrumError::ParseError(ParseError::UnterminatedBar));
+            return Err(parse_error(ParseError::UnterminatedBar, last_span));
+        }
+        if let Some((_, span)) = section {
+            return Err(parse_error(ParseError::SectionPlacement, span));
+        }
+        if let Some((_, span)) = groups.first() {
+            return Err(parse_error(ParseError::UnclosedGroup, *span));
+        }
+        if let Some((_, span)) = repeat_start {
+            return Err(parse_error(ParseError::UnclosedRepeat, span));
+        }
+
+        let bar_starts: Vec<Rational> = bars
+            .iter()
+            .scan(Rational::zero(), |start, bar| {
+                let bar_start = *start;
+                *start += bar.length();
+                Some(bar_start)
+            })
+            .collect();
+        for i in 0..tempo_map.len() {
+            let next = tempo_map.get(i + 1).map(|m| m.position).unwrap_or(length);
+            if let Some(ramp) = tempo_map[i].ramp.as_mut() {
+                ramp.end = next;
+            }
+        }
+        for (index, bar_index, n) in ramp_bars {
+            if let Some(ramp) = tempo_map[index].ramp.as_mut() {
+                ramp.end = bar_starts
+                    .get(bar_index + n as usize)
+                    .copied()
+                    .unwrap_or(length);
+            }
+        }
+
+        let mut score = Score {
+            bars,
+            tempo_map,
+            count_in,
+        };
+        score.retime();
+        Ok(score)
+    }
+
+    /// Calculates the time in *milliseconds* from the start of the score to a position given in
+    /// whole notes, following every tempo change and ramp in the tempo map
+    pub fn time_at(&self, position: Rational) -> Rational {
+        let mut ms = Rational::zero();
+        for (i, mark) in self.tempo_map.iter().enumerate() {
+            if mark.position >= position {
+                break;
+            }
+            let next = match self.tempo_map.get(i + 1) {
+                Some(next) if next.position < position => next.position,
+                _ => position,
+            };
+            ms += mark.elapsed_ms(next - mark.position);
+        }
+        ms
+    }
+
+    /// Returns the tempo mark in effect at a position given in whole notes
+    pub fn tempo_mark_at(&self, position: Rational) -> &TempoMark {
+        self.tempo_map
+            .iter()
+            .rev()
+            .find(|m| m.position <= position)
+            .unwrap_or(&self.tempo_map[0])
+    }
+
+    /// Returns the tempo at a position given in whole notes, following ramps
+    pub fn tempo_at(&self, position: Rational) -> Tempo {
+        let mark = self.tempo_mark_at(position);
+        mark.tempo_at(position.max(mark.position) - mark.position)
+    }
+
+    /// Returns the start of every bar of the click track, from the count-in to the last bar
+    pub fn bar_starts(&self) -> Vec<BarStart> {
+        let meter_of = |bar: &Bar| {
+            bar.meter
+                .clone()
+                .unwrap_or_else(|| Meter::from_length(bar.length()))
+        };
+        let mut starts = Vec::new();
+        let mut position = Rational::zero();
+        let mut ms = Rational::zero();
+        for bar in self.count_in_bars().iter() {
+            starts.push(BarStart {
+                number: None,
+                position,
+                ms,
+                tempo: self.tempo_map[0].tempo,
+                meter: meter_of(bar),
+                section: None,
+            });
+            position += bar.length();
+            ms += bar
+                .durations
+                .iter()
+                .fold(Rational::zero(), |acc, d| acc + d.ms);
+        }
+        let (count_in, count_in_ms) = (position, ms);
+        let mut start = Rational::zero();
+        for (i, bar) in self.bars.iter().enumerate() {
+            starts.push(BarStart {
+                number: Some(i + 1),
+                position: count_in + start,
+                ms: count_in_ms + self.time_at(start),
+                tempo: self.tempo_at(start),
+                meter: meter_of(bar),
+                section: bar.section.clone(),
+            });
+            start += bar.length();
+        }
+        starts
+    }
+
+    /// Sets the number of subdivision clicks per beat for every bar, or turns them off with
+    /// `None`
+    pub fn set_subdivision(&mut self, subdivision: Option<u16>) {
+        for bar in self.bars.iter_mut() {
+            bar.subdivision = subdivision;
+        }
+    }
+
+    /// Makes every tempo and ramp of the score `factor` times as fast. Fails without changing
+    /// the score if a tempo would become too slow to be represented, which also happens for a
+    /// factor of 0.
+    pub fn scale_tempo(&mut self, factor: Rational) -> Result<(), TempoError> {
+        let mut tempo_map = self.tempo_map.clone();
+        for mark in tempo_map.iter_mut() {
+            mark.tempo = mark.tempo.scaled(factor);
+            if let Some(ramp) = mark.ramp.as_mut() {
+                ramp.target = ramp.target * factor;
+            }
+        }
+        check_tempos(&tempo_map, &self.tempo_map)?;
+        self.tempo_map = tempo_map;
+        self.retime();
+        Ok(())
+    }
+
+    /// Adds `offset` beats per minute to every tempo written in the score and to the target of
+    /// every ramp, each counted in its own beat. Tempos that follow from the tempo before, like
+    /// after `q = q.`, keep their proportion to it, or to the default tempo at the start of the
+    /// score. Fails without changing the score if a tempo would drop to 0 beats per minute or
+    /// below, or become too fast to be represented.
+    pub fn offset_tempo(&mut self, offset: f64) -> Result<(), TempoError> {
+        let shift = |num_beats: Rational| {
+            let shifted = num_beats.as_f64() + offset;
+            match Rational::approximate_positive(shifted) {
+                Some(shifted) => Ok(shifted),
+                None if shifted > 1.0 => Err(TempoError::TooFast(num_beats)),
+                None => Err(TempoError::NotPositive(num_beats)),
+            }
+        };
+        let mut tempo_map = self.tempo_map.clone();
+        // how much faster the tempo before has become, starting from the default tempo if the
+        // score opens with a relative tempo change
+        let default = Tempo::default().num_beats;
+        let mut factor = match tempo_map.first() {
+            Some(mark) if mark.relative => shift(default)? / default,
+            _ => Rational::from(1),
+        };
+        for mark in tempo_map.iter_mut() {
+            let num_beats = match mark.relative {
+                true => mark.tempo.num_beats * factor,
+                false => shift(mark.tempo.num_beats)?,
+            };
+            factor = num_beats / mark.tempo.num_beats;
+            mark.tempo.num_beats = num_beats;
+            if let Some(ramp) = mark.ramp.as_mut() {
+                let target = shift(ramp.target)?;
+                factor = target / ramp.target;
+                ramp.target = target;
+            }
+        }
+        check_tempos(&tempo_map, &self.tempo_map)?;
+        self.tempo_map = tempo_map;
+        self.retime();
+        Ok(())
+    }
+
+    /// Returns the positions in whole notes of every subdivision click. Each bar with a
+    /// subdivision divides the beat of the tempo in effect into equal parts, starting again from
+    /// every tempo change. Positions that fall on a note are left out.
+    pub fn subdivision_positions(&self) -> Vec<Rational> {
+        let mut positions = Vec::new();
+        let mut bar_start = Rational::zero();
+        for bar in self.bars.iter() {
+            let bar_end = bar_start + bar.length();
+            let Some(n) = bar.subdivision.filter(|n| *n > 0) else {
+                bar_start = bar_end;
+                continue;
+            };
+            let mut notes = Vec::new();
+            let mut onset = bar_start;
+            for dur in bar.durations.iter() {
+                if !dur.is_rest() {
+                    notes.push(onset);
+                }
+                onset += dur.value;
+            }
+            let mut segments = vec![bar_start];
+            segments.extend(
+                self.tempo_map
+                    .iter()
+                    .map(|m| m.position)
+                    .filter(|p| *p > bar_start && *p < bar_end),
+            );
+            segments.push(bar_end);
+            for segment in segments.windows(2) {
+                let step = self.tempo_mark_at(segment[0]).tempo.beat / Rational::from(n as u64);
+                let mut position = segment[0];
+                while position < segment[1] {
+                    if !notes.contains(&position) {
+                        positions.push(position);
+                    }
+                    position += step;
+                }
+            }
+            bar_start = bar_end;
+        }
+        positions
+    }
+
+    /// Creates the bars that are counted in before the first bar. They are played at the opening
+    /// tempo and in the meter of the first bar: one click per beat, or per beat group for meters
+    /// counted in eighths or shorter. Without a time signature, the first bar is counted in beats
+    /// of the opening tempo.
+    pub fn count_in_bars(&self) -> Vec<Bar> {
+        let Some(first) = self.bars.first() else {
+            return Vec::new();
+        };
+        let tempo = self.tempo_map[0].tempo;
+        let beats: Vec<Rational> = match &first.meter {
+            Some(meter) if meter.bottom >= 8 => meter
+                .groups
+                .iter()
+                .map(|g| Rational::new(*g as u64, meter.bottom as u64))
+                .collect(),
+            Some(meter) => vec![Rational::new(1, meter.bottom as u64); meter.top as usize],
+            None => {
+                let mut remaining = first.length();
+                let mut beats = Vec::new();
+                while remaining >= tempo.beat {
+                    beats.push(tempo.beat);
+                    remaining = remaining - tempo.beat;
+                }
+                if !remaining.is_zero() {
+                    beats.push(remaining);
+                }
+                beats
+            }
+        };
+        let bar = Bar {
+            durations: beats
+                .iter()
+                .enumerate()
+                .map(|(i, value)| Duration {
+                    ms: tempo.duration_of(*value),
+                    value: *value,
+                    accent: match i {
+                        0 => Accent::Downbeat,
+                        _ => Accent::Unaccented,
+                    },
+                    kind: EventKind::Note,
+                })
+                .collect(),
+            meter: first.meter.clone(),
+            subdivision: None,
+            section: None,
+        };
+        vec![bar; self.count_in as usize]
+    }
+
+    /// Recalculates the length in *milliseconds* of every duration from the tempo map
+    pub fn retime(&mut self) {
+        let mut position = Rational::zero();
+        let mut onset = Rational::zero();
+        let mut bars = std::mem::take(&mut self.bars);
+        for dur in bars.iter_mut().flat_map(|bar| bar.durations.iter_mut()) {
+            position += dur.value;
+            let end = self.time_at(position);
+            dur.ms = end - onset;
+            onset = end;
         }
+        self.bars = bars;
+    }
+
+    /// Calculates the length of the score in whole notes
+    pub fn length(&self) -> Rational {
+        self.bars
+            .iter()
+            .fold(Rational::zero(), |acc, bar| acc + bar.length())
+    }
 
-        Ok(Score { bars })
+    /// Calculates the length of the count-in in whole notes and in *milliseconds*
+    pub fn count_in_length(&self) -> (Rational, Rational) {
+        self.count_in_bars()
+            .iter()
+            .flat_map(|bar| bar.durations.iter())
+            .fold((Rational::zero(), Rational::zero()), |(wholes, ms), dur| {
+                (wholes + dur.value, ms + dur.ms)
+            })
+    }
+
+    /// Returns every click of the click track, from the count-in to the last note. Rests are
+    /// left out, but silent notes are not.
+    pub fn clicks(&self) -> Vec<Click> {
+        let mut clicks = Vec::new();
+        let mut position = Rational::zero();
+        let mut ms = Rational::zero();
+        for dur in self
+            .count_in_bars()
+            .iter()
+            .flat_map(|bar| bar.durations.iter())
+        {
+            clicks.push(Click {
+                position,
+                ms,
+                kind: ClickKind::CountIn(dur.accent),
+            });
+            position += dur.value;
+            ms += dur.ms;
+        }
+        let (count_in, count_in_ms) = (position, ms);
+        for subdivision in self.subdivision_positions() {
+            clicks.push(Click {
+                position: count_in + subdivision,
+                ms: count_in_ms + self.time_at(subdivision),
+                kind: ClickKind::Subdivision,
+            });
+        }
+        for dur in self.bars.iter().flat_map(|bar| bar.durations.iter()) {
+            if !dur.is_rest() {
+                clicks.push(Click {
+                    position,
+                    ms,
+                    kind: ClickKind::Note(dur.accent),
+                });
+            }
+            position += dur.value;
+            ms += dur.ms;
+        }
+        clicks
     }
 
     /// Calculates and returns the total duration of the score in *milliseconds*.
-    pub fn total_duration(&self) -> f32 {
-        self.bars.iter().fold(0.0, |acc, e| {
-            acc + e.durations.iter().fold(0.0, |acc, e| acc + e.ms)
+    pub fn total_duration(&self) -> Rational {
+        self.bars.iter().fold(Rational::zero(), |acc, e| {
+            acc + e
+                .durations
+                .iter()
+                .fold(Rational::zero(), |acc, e| acc + e.ms)
         })
     }
+
+    /// Returns the exact onset of every duration in the score in *milliseconds*, measured from
+    /// the start of the score
+    pub fn onsets(&self) -> Vec<Rational> {
+        let mut onset = Rational::zero();
+        let mut onsets = Vec::new();
+        for dur in self.bars.iter().flat_map(|bar| bar.durations.iter()) {
+            onsets.push(onset);
+            onset += dur.ms;
+        }
+        onsets
+    }
+}
+
+/// Copies bars to be played again. The copies carry no rehearsal mark, so that every section is
+/// named once and the copies belong to the section they are played in.
+fn copy_bars(bars: &[Bar]) -> Vec<Bar> {
+    bars.iter()
+        .map(|bar| Bar {
+            section: None,
+            ..bar.clone()
+        })
+        .collect()
+}
+
+/// Checks that every tempo and ramp target of a changed tempo map still lasts a finite time per
+/// beat. Fails with the tempo it was changed from otherwise.
+fn check_tempos(tempo_map: &[TempoMark], original: &[TempoMark]) -> Result<(), TempoError> {
+    for (mark, before) in tempo_map.iter().zip(original) {
+        let too_slow = |num_beats| {
+            Tempo::new(mark.tempo.beat, num_beats)
+                .wholes_per_min()
+                .is_zero()
+        };
+        if too_slow(mark.tempo.num_beats) {
+            return Err(TempoError::NotPositive(before.tempo.num_beats));
+        }
+        if let (Some(ramp), Some(ramp_before)) = (mark.ramp, before.ramp) {
+            if too_slow(ramp.target) {
+                return Err(TempoError::NotPositive(ramp_before.target));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Copies the tempo marks inside `range`, given as the start and end positions and the indices of
+/// the first bar and of the bar after it, to `destination`, given as a position and the index of
+/// the bar starting there, so that repeated bars keep the tempo changes written inside them. Ramps
+/// that last longer than the repeated bars are not copied but carry on through the repeats.
+fn repeat_tempo_marks(
+    tempo_map: &mut Vec<TempoMark>,
+    ramp_bars: &mut Vec<(usize, usize, u16)>,
+    range: (Rational, Rational, usize, usize),
+    destination: (Rational, usize),
+) {
+    let (start, end, first_bar, end_bar) = range;
+    let (position, bar_index) = destination;
+    let inside: Vec<(usize, TempoMark)> = tempo_map
+        .iter()
+        .copied()
+        .enumerate()
+        .filter(|(_, m)| m.position >= start && m.position < end)
+        .filter(|(index, m)| match m.ramp {
+            None => true,
+            Some(_) => match ramp_bars.iter().find(|(i, ..)| i == index) {
+                Some((_, bar, n)) => *bar >= first_bar && bar + *n as usize <= end_bar,
+                // without a number of bars, a ramp lasts until the next mark
+                None => tempo_map
+                    .get(index + 1)
+                    .is_some_and(|next| next.position < end),
+            },
+        })
+        .collect();
+    let before = tempo_map
+        .iter()
+        .rev()
+        .find(|m| m.position <= start)
+        .unwrap();
+    // the copy has to start in the tempo the repeated bars started in, unless a ramp that started
+    // with them carries on
+    let changed = tempo_map.last().unwrap().position > start;
+    let restarts = inside.first().is_some_and(|(_, m)| m.position == start);
+    if changed && !restarts && before.position != start {
+        let tempo = match before.ramp {
+            Some(ramp) => Tempo::new(before.tempo.beat, ramp.target),
+            None => before.tempo,
+        };
+        // the tempo is restated rather than following from the one before, so that it changes
+        // with the tempo it restates
+        tempo_map.push(TempoMark {
+            position,
+            tempo,
+            ramp: None,
+            relative: false,
+        });
+    }
+    for (index, mark) in inside {
+        if let Some(&(_, bar, n)) = ramp_bars.iter().find(|(i, ..)| *i == index) {
+            if let Some(offset) = bar.checked_sub(first_bar) {
+                ramp_bars.push((tempo_map.len(), offset + bar_index, n));
+            }
+        }
+        tempo_map.push(TempoMark {
+            position: mark.position - start + position,
+            ..mark
+        });
+    }
+}
+
+/// Parses a number of beats per minute: a whole or decimal number, or a ratio in parentheses like
+/// `(185/2)`. Returns `None` without taking any token if none of these follows.
+fn parse_beats_per_minute(
+    tokens: &mut Peekable<Iter<Spanned>>,
+    error: impl Fn(Span) -> MetrumError,
+) -> Result<Option<Rational>, MetrumError> {
+    if let Some(t) = tokens.next_if(|t| t.token.as_beats_per_minute().is_some()) {
+        return Ok(t.token.as_beats_per_minute());
+    }
+    let Some(paren) = tokens.next_if(|t| t.token == Token::LeftParen) else {
+        return Ok(None);
+    };
+    let bpm = match tokens.next() {
+        Some(Spanned {
+            token: Token::Ratio(top, bottom),
+            ..
+        }) => Rational::new(*top as u64, *bottom as u64),
+        Some(t) => return Err(error(t.span)),
+        None => return Err(error(paren.span)),
+    };
+    match tokens.next() {
+        Some(Spanned {
+            token: Token::RightParen,
+            ..
+        }) => Ok(Some(bpm)),
+        Some(t) => Err(error(t.span)),
+        None => Err(error(paren.span)),
+    }
+}
+
+/// Parses what follows a [Token::Ramp]: the target tempo and an optional `@` with the number of
+/// bars the ramp lasts
+fn parse_ramp(
+    tokens: &mut Peekable<Iter<Spanned>>,
+    span: Span,
+) -> Result<(Rational, Option<u16>), MetrumError> {
+    let error = |span: Span| MetrumError::ParseError(ParseError::Ramp, span);
+    let Some(target) = parse_beats_per_minute(tokens, error)? else {
+        return Err(error(tokens.peek().map_or(span, |t| t.span)));
+    };
+    let Some(at) = tokens.next_if(|t| t.token == Token::At) else {
+        return Ok((target, None));
+    };
+    match tokens.next() {
+        Some(Spanned {
+            token: Token::Number(n),
+            ..
+        }) => Ok((target, Some(*n))),
+        Some(t) => Err(error(t.span)),
+        None => Err(error(at.span)),
+    }
+}
+
+/// Parses the time signature following a [Directive::Time], e.g. `4/4` or `7/8 (2+2+3)`
+fn parse_meter(tokens: &mut Peekable<Iter<Spanned>>, span: Span) -> Result<Meter, MetrumError> {
+    let error = |span: Span| MetrumError::ParseError(ParseError::TimeSignature, span);
+    let (top, bottom, mut span) = match tokens.next() {
+        Some(Spanned {
+            token: Token::Ratio(top, bottom),
+            span: ratio_span,
+        }) => (*top, *bottom, span.to(*ratio_span)),
+        Some(t) => return Err(error(t.span)),
+        None => return Err(error(span)),
+    };
+    let mut meter = Meter::new(top, bottom);
+    if tokens.next_if(|t| t.token == Token::LeftParen).is_none() {
+        return Ok(meter);
+    }
+    meter.groups.clear();
+    loop {
+        match tokens.next() {
+            Some(Spanned {
+                token: Token::Number(n),
+                ..
+            }) => meter.groups.push(*n),
+            Some(t) => return Err(error(t.span)),
+            None => return Err(error(span)),
+        }
+        match tokens.next() {
+            Some(Spanned {
+                token: Token::Plus, ..
+            }) => {}
+            Some(Spanned {
+                token: Token::RightParen,
+                span: paren_span,
+            }) => {
+                span = span.to(*paren_span);
+                break;
+            }
+            Some(t) => return Err(error(t.span)),
+            None => return Err(error(span)),
+        }
+    }
+    if meter.groups.iter().map(|g| *g as u32).sum::<u32>() != top as u32 {
+        return Err(MetrumError::ParseError(
+            ParseError::TimeSignatureGroups,
+            span,
+        ));
+    }
+    Ok(meter)
 }
 
 impl std::fmt::Display for Score {
@@ -185,7 +1249,10 @@ impl std::fmt::Display for Score {
         for bar in self.bars.iter() {
             out += "| ";
             for dur in bar.durations.iter() {
-                out += format!(" {} ", dur.ms).as_str();
+                match dur.kind {
+                    EventKind::Note => out += format!(" {} ", dur.ms).as_str(),
+                    EventKind::Rest => out += format!(" {}r ", dur.ms).as_str(),
+                }
             }
         }
         out += " |";
@@ -203,19 +1270,65 @@ mod tests {
         let score = Score::new(toks);
         assert_eq!(
             score.unwrap_err(),
-            error::MetrumError::ParseError(ParseError::MissingTempoSpecifier)
+            error::MetrumError::ParseError(
+                ParseError::MissingTempoSpecifier,
+                Span {
+                    line: 1,
+                    column: 3,
+                    start: 2,
+                    end: 3
+                }
+            )
         );
+
+        let data = ["q = (185/2", "q = ()", "q = (92)", "q = (185/2 q"];
+        for d in data.iter() {
+            let toks = scan(d.to_string()).unwrap();
+            assert!(
+                matches!(
+                    Score::new(toks),
+                    Err(MetrumError::ParseError(
+                        ParseError::MissingTempoSpecifier,
+                        _
+                    ))
+                ),
+                "{d}"
+            );
+        }
+    }
+
+    #[test]
+    fn tempo_ratios() {
+        // a ratio that can't be a note is a tempo missing its parentheses
+        let data = [
+            ("q = 184/2", 5),
+            ("| q q = 9/2 q |", 9),
+            ("q = 4/1. | q |", 5),
+        ];
+        for (s, column) in data {
+            match Score::new(scan(s.to_string()).unwrap()) {
+                Err(MetrumError::ParseError(ParseError::TempoRatio, span)) => {
+                    assert_eq!(span.column, column, "{s}");
+                }
+                other => panic!("expected a tempo ratio error for {s}, got {other:?}"),
+            }
+        }
+        assert!(Score::new(scan("q = 4/1 | q |".to_string()).unwrap()).is_ok());
     }
 
     #[test]
     fn tempo_changes() {
-        let data = vec![
-            ("| q q=h q|", 250.0),
-            ("|q q=q. q.|", 500.0),
-            ("|q q=1/4. q.|", 500.0),
-            ("|q 1/4=1/4. q.|", 500.0),
-            ("|h 2/4=2/4. h.|", 1000.0),
-            ("|q q.=q q|", 750.0),
+        let data = [
+            ("| q q=h q|", Rational::from(250)),
+            ("|q q=q. q.|", Rational::from(500)),
+            ("|q q=1/4. q.|", Rational::from(500)),
+            ("|q 1/4=1/4. q.|", Rational::from(500)),
+            ("|h 2/4=2/4. h.|", Rational::from(1000)),
+            ("|q q.=q q|", Rational::from(750)),
+            ("|q q=1/6 1/6|", Rational::from(500)),
+            ("|q q=1/6 q|", Rational::from(750)),
+            ("|q q=1/7 1/6 1/6 1/6|", Rational::new(3500, 6)),
+            ("q=7 |q q=1/6 q|", Rational::new(60_000 * 6, 4 * 7)),
         ];
         for (s, duration) in data.iter() {
             let toks = scan(s.to_string()).unwrap();
@@ -225,9 +1338,24 @@ mod tests {
         }
     }
 
+    #[test]
+    fn dotted_beats() {
+        let data = [
+            ("h. = 130 | h. |", Rational::new(60_000, 130)),
+            ("q. = 80 | q. q |", Rational::from(500)),
+            ("q.. = 60 | e |", Rational::new(2000, 7)),
+        ];
+        for (s, duration) in data.iter() {
+            let toks = scan(s.to_string()).unwrap();
+            let score = Score::new(toks).unwrap();
+            let note = score.bars[0].durations.last().unwrap();
+            assert_eq!(note.ms, *duration, "{s}");
+        }
+    }
+
     #[test]
     fn setting_tempo() {
-        let data = vec!["q = 140", "1/3=120", "q. = 80", "1/5.=200"];
+        let data = ["q = 140", "1/3=120", "q. = 80", "1/5.=200"];
         for d in data.iter() {
             let toks = scan(d.to_string()).unwrap();
             let score = Score::new(toks);
@@ -237,11 +1365,17 @@ mod tests {
 
     #[test]
     fn total_durations() {
-        let data = vec![
-            ("| qx4 |", 2000.0),
-            ("q=60 | qx4 |", 4000.0),
-            ("q=240 | qx4 |", 1000.0),
-            ("| qx3 |", 1500.0),
+        let data = [
+            ("| qx4 |", Rational::from(2000)),
+            ("q=60 | qx4 |", Rational::from(4000)),
+            ("q=240 | qx4 |", Rational::from(1000)),
+            ("| qx3 |", Rational::from(1500)),
+            ("q=70 | 1/3x3 |", Rational::new(24000, 7)),
+            ("q=70 | 1/3x3 | q=1/6 1/3x3 |", Rational::new(60000, 7)),
+            ("q=92.5 | qx4 |", Rational::new(480000, 185)),
+            ("q=0.5 | q |", Rational::from(120_000)),
+            ("q=(184/2) | qx4 |", Rational::new(240000, 92)),
+            ("q=4/1 | 4/1 |", Rational::from(500)),
         ];
         for (d, dur) in data.iter() {
             let toks = scan(d.to_string()).unwrap();
@@ -252,7 +1386,7 @@ mod tests {
 
     #[test]
     fn bar_repeats() {
-        let data = vec![("| q |%2", 2)];
+        let data = [("| q |%2", 2)];
         for (d, num_bars) in data.iter() {
             let toks = scan(d.to_string()).unwrap();
             let score = Score::new(toks).unwrap();
@@ -262,21 +1396,94 @@ mod tests {
 
     #[test]
     fn note_repeats() {
-        let data = vec![("| qx4 |", 4), ("| qx4 hx2 |", 6), ("| qx100 |", 100)];
+        let data = [("| qx4 |", 4), ("| qx4 hx2 |", 6), ("| qx100 |", 100)];
         for (d, num_notes) in data.iter() {
             let toks = scan(d.to_string()).unwrap();
             let score = Score::new(toks).unwrap();
             assert_eq!(score.bars[0].durations.len(), *num_notes);
-            assert!(score.bars[0].durations[0].strong);
+            assert_eq!(score.bars[0].durations[0].accent, Accent::Downbeat);
             for i in 1..score.bars[0].durations.len() {
-                assert!(
-                    !score.bars[0].durations[i].strong,
+                assert_eq!(
+                    score.bars[0].durations[i].accent,
+                    Accent::Unaccented,
                     "The rest of the beats in a bar must be weak"
                 );
             }
         }
     }
 
+    #[test]
+    fn group_repeats_of_notes() {
+        use Accent::*;
+        let values = |s: &str| -> Vec<Rational> {
+            let score = Score::new(scan(s.to_string()).unwrap()).unwrap();
+            score.bars[0].durations.iter().map(|d| d.value).collect()
+        };
+        let (e, q, dq) = (
+            Rational::new(1, 8),
+            Rational::new(1, 4),
+            Rational::new(3, 8),
+        );
+        let data = [
+            ("| (q. e)x4 |", vec![dq, e, dq, e, dq, e, dq, e]),
+            (
+                "| (e e q)x2 h |",
+                vec![e, e, q, e, e, q, q * Rational::from(2)],
+            ),
+            (
+                "| ((e e)x2 q)x2 h |",
+                vec![e, e, e, e, q, e, e, e, e, q, q * Rational::from(2)],
+            ),
+            ("| q (e)x2 h |", vec![q, e, e, q * Rational::from(2)]),
+        ];
+        for (s, expected) in data.iter() {
+            assert_eq!(values(s), *expected, "{s}");
+        }
+
+        // only the first note of the bar gets the downbeat, written accents are repeated
+        let data = [
+            (
+                "| (q e e)x2 |",
+                vec![
+                    Downbeat, Unaccented, Unaccented, Unaccented, Unaccented, Unaccented,
+                ],
+            ),
+            (
+                "| (q >e e')x2 |",
+                vec![Downbeat, Secondary, Ghost, Unaccented, Secondary, Ghost],
+            ),
+            (
+                "#time 6/8 | (e e e)x2 |",
+                vec![
+                    Downbeat, Unaccented, Unaccented, Secondary, Unaccented, Unaccented,
+                ],
+            ),
+        ];
+        for (s, accents) in data.iter() {
+            let score = Score::new(scan(s.to_string()).unwrap()).unwrap();
+            let output: Vec<Accent> = score.bars[0].durations.iter().map(|d| d.accent).collect();
+            assert_eq!(output, *accents, "{s}");
+        }
+
+        let data = [
+            ("| (q e |", ParseError::UnclosedGroup, 3),
+            ("| (q e) |", ParseError::GroupRepeat, 7),
+            ("| q e)x2 |", ParseError::GroupRepeat, 6),
+            ("| (q | e)x2 |", ParseError::UnclosedGroup, 3),
+            ("| q ()x2 |", ParseError::NothingToRepeat, 6),
+            ("| q + q |", ParseError::Grouping, 5),
+        ];
+        for (s, error, column) in data.iter() {
+            match Score::new(scan(s.to_string()).unwrap()) {
+                Err(MetrumError::ParseError(e, span)) => {
+                    assert_eq!(e, *error, "{s}");
+                    assert_eq!(span.column, *column, "{s}");
+                }
+                _ => panic!("expected a parse error for {s}"),
+            }
+        }
+    }
+
     #[test]
     fn single_bar() {
         let toks = scan("| q q q q |".to_string()).unwrap();
@@ -294,7 +1501,7 @@ mod tests {
                 .len(),
             4
         );
-        assert!(
+        assert_eq!(
             score
                 .as_ref()
                 .unwrap()
@@ -304,10 +1511,11 @@ mod tests {
                 .durations
                 .first()
                 .unwrap()
-                .strong
+                .accent,
+            Accent::Downbeat
         );
-        assert!(
-            !score
+        assert_eq!(
+            score
                 .as_ref()
                 .unwrap()
                 .bars
@@ -316,10 +1524,644 @@ mod tests {
                 .durations
                 .get(1)
                 .unwrap()
-                .strong
+                .accent,
+            Accent::Unaccented
         )
     }
 
+    #[test]
+    fn error_spans() {
+        let data = [
+            ("| q |\n| q q %2 |", ParseError::BarRepeat, 2, 7),
+            ("| q q\nq q", ParseError::UnterminatedBar, 2, 3),
+            ("| qx2 |\n  = 120", ParseError::Equal, 2, 3),
+        ];
+        for (s, error, line, column) in data.iter() {
+            let score = Score::new(scan(s.to_string()).unwrap());
+            match score {
+                Err(MetrumError::ParseError(e, span)) => {
+                    assert_eq!(e, *error);
+                    assert_eq!((span.line, span.column), (*line, *column), "{s}");
+                }
+                _ => panic!("expected a parse error for {s}"),
+            }
+        }
+    }
+
+    #[test]
+    fn rests() {
+        let data = [
+            ("| q qr q q |", vec![false, true, false, false], 2000),
+            ("| qr. e hr |", vec![true, false, true], 2000),
+            ("| q.r e |", vec![true, false], 1000),
+            ("| 1/8r 1/8r. |", vec![true, true], 625),
+            ("| h qrx2 |", vec![false, true, true], 2000),
+        ];
+        for (d, rests, total) in data.iter() {
+            let toks = scan(d.to_string()).unwrap();
+            let score = Score::new(toks).unwrap();
+            let output: Vec<bool> = score.bars[0]
+                .durations
+                .iter()
+                .map(|d| d.is_rest())
+                .collect();
+            assert_eq!(output, *rests, "{d}");
+            assert_eq!(score.total_duration(), Rational::from(*total), "{d}");
+        }
+    }
+
+    #[test]
+    fn accents() {
+        use Accent::*;
+        let data = [
+            (
+                "| q >q q q |",
+                vec![Downbeat, Secondary, Unaccented, Unaccented],
+            ),
+            ("| q' q ^e. s_ |", vec![Ghost, Unaccented, Downbeat, Silent]),
+            ("| >q h' q. |", vec![Secondary, Ghost, Unaccented]),
+            (
+                "| q >ex3 e'x2 |",
+                vec![Downbeat, Secondary, Secondary, Secondary, Ghost, Ghost],
+            ),
+            ("| qx2 qr. |", vec![Downbeat, Unaccented, Unaccented]),
+        ];
+        for (d, accents) in data.iter() {
+            let score = Score::new(scan(d.to_string()).unwrap()).unwrap();
+            let output: Vec<Accent> = score.bars[0].durations.iter().map(|d| d.accent).collect();
+            assert_eq!(output, *accents, "{d}");
+        }
+    }
+
+    #[test]
+    fn misplaced_accents() {
+        let data = [
+            "| > |", "| q > |", "| ' q |", "| qr' |", "| >qr |", "| ^qr. |", ">q = 120", "| q ^ |",
+        ];
+        for d in data.iter() {
+            let toks = scan(d.to_string()).unwrap();
+            assert!(Score::new(toks).is_err(), "{d}");
+        }
+    }
+
+    #[test]
+    fn time_signatures() {
+        use Accent::*;
+        let data = [
+            (
+                "#time 4/4 | qx4 |",
+                vec![Downbeat, Unaccented, Secondary, Unaccented],
+            ),
+            (
+                "#time 3/4 | q q q |",
+                vec![Downbeat, Unaccented, Unaccented],
+            ),
+            (
+                "#time 6/8 | ex6 |",
+                vec![
+                    Downbeat, Unaccented, Unaccented, Secondary, Unaccented, Unaccented,
+                ],
+            ),
+            (
+                "#time 7/8 (2+2+3) | ex7 |",
+                vec![
+                    Downbeat, Unaccented, Secondary, Unaccented, Secondary, Unaccented, Unaccented,
+                ],
+            ),
+            (
+                "#time 5/8 (3+2) | q. e' e |",
+                vec![Downbeat, Ghost, Unaccented],
+            ),
+            (
+                "#time 4/4 | q. e h |",
+                vec![Downbeat, Unaccented, Secondary],
+            ),
+        ];
+        for (d, accents) in data.iter() {
+            let score = Score::new(scan(d.to_string()).unwrap()).unwrap();
+            let output: Vec<Accent> = score.bars[0].durations.iter().map(|d| d.accent).collect();
+            assert_eq!(output, *accents, "{d}");
+            assert!(score.bars[0].meter.is_some());
+        }
+    }
+
+    #[test]
+    fn time_signature_changes() {
+        let toks = scan("| q q | #time 3/4 | q q q | h. |%2 #time 2/4 | h |".to_string());
+        let score = Score::new(toks.unwrap()).unwrap();
+        let meters: Vec<Option<(u16, u16)>> = score
+            .bars
+            .iter()
+            .map(|b| b.meter.as_ref().map(|m| (m.top, m.bottom)))
+            .collect();
+        assert_eq!(
+            meters,
+            vec![None, Some((3, 4)), Some((3, 4)), Some((3, 4)), Some((2, 4))]
+        );
+    }
+
+    #[test]
+    fn bar_lengths() {
+        let toks = scan("#time 3/4 | q q q |\n| q q q q |".to_string()).unwrap();
+        match Score::new(toks) {
+            Err(MetrumError::ParseError(e, span)) => {
+                assert_eq!(
+                    e,
+                    ParseError::BarLength {
+                        bar: 2,
+                        expected: Rational::new(3, 4),
+                        actual: Rational::from(1)
+                    }
+                );
+                assert_eq!((span.line, span.column), (2, 11));
+            }
+            other => panic!("expected a bar length error, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn invalid_time_signatures() {
+        let data = [
+            "#time",
+            "#time = 4",
+            "#time 4",
+            "#time 7/8 (2+2)",
+            "#time 7/8 (2+2+3",
+            "#time 7/8 (2 2 3)",
+            "| q #time 3/4 q q |",
+            "| q (2) |",
+            "| q + q |",
+        ];
+        for d in data.iter() {
+            let toks = scan(d.to_string()).unwrap();
+            assert!(Score::new(toks).is_err(), "{d}");
+        }
+    }
+
+    #[test]
+    fn ramps() {
+        let data = [
+            ("q = 60 ~> 120 | qx4 | q = 120 q |", 2772.589),
+            ("q = 60 ~~> 120 | qx4 | q = 120 q |", 2885.390),
+            ("q = 60 ~> 120 @1 | qx4 | q |", 2772.589),
+            ("q = 60 | q q ~> 120 qx3 | q = 120 q |", 1000.0 + 2079.442),
+            ("q = 120 ~> 60 @2 | h h | h h | q |", 2301.457),
+            ("q = 60 ~> 60 | qx4 | q |", 4000.0),
+            ("q = (120/2) ~> (240/2) | qx4 | q = 120 q |", 2772.589),
+        ];
+        for (d, first_bar) in data.iter() {
+            let score = Score::new(scan(d.to_string()).unwrap()).unwrap();
+            let ms = score.bars[0]
+                .durations
+                .iter()
+                .fold(Rational::zero(), |acc, d| acc + d.ms)
+                .as_f64();
+            assert!((ms - first_bar).abs() < 0.001, "{d}: {ms}");
+        }
+    }
+
+    #[test]
+    fn ramp_targets() {
+        let toks = scan("q = 60 ~> 120 @1 | qx4 | qx4 | h = q. q |".to_string()).unwrap();
+        let score = Score::new(toks).unwrap();
+        let accelerating = &score.bars[0].durations;
+        for i in 1..accelerating.len() {
+            assert!(accelerating[i].ms < accelerating[i - 1].ms);
+        }
+        assert_eq!(score.bars[1].durations[0].ms, Rational::from(500));
+        // relative tempo changes after a ramp are relative to its target
+        assert_eq!(score.bars[2].durations[0].ms, Rational::new(2000, 3));
+        assert_eq!(score.tempo_map.len(), 2);
+
+        // a ramp written at the end of a bar counts its bars from the next one
+        let ms = |s: &str| -> Vec<Rational> {
+            let score = Score::new(scan(s.to_string()).unwrap()).unwrap();
+            score.bars[1].durations.iter().map(|d| d.ms).collect()
+        };
+        let at_end = ms("| q q = 60 ~> 120 @1 | qx4 | q |");
+        assert_eq!(at_end, ms("| q | q = 60 ~> 120 @1 qx4 | q |"));
+        assert_eq!(at_end[0], ms("| q | q = 60 ~> 120 qx4 | q = 120 q |")[0]);
+
+        let toks = scan("q = 60 ~> 92.5 @1 | qx4 | q |".to_string()).unwrap();
+        let score = Score::new(toks).unwrap();
+        assert_eq!(score.bars[1].durations[0].ms, Rational::new(120_000, 185));
+    }
+
+    #[test]
+    fn tempo_transforms() {
+        let ms = |score: &Score| -> Vec<Rational> {
+            score.bars.iter().map(|b| b.durations[0].ms).collect()
+        };
+        let s = "q = 120 | q q | q = q. q. | q = 60 ~> 90 | q q | q = h q q |";
+        let mut score = Score::new(scan(s.to_string()).unwrap()).unwrap();
+        score.scale_tempo(Rational::new(1, 2)).unwrap();
+        assert_eq!(score.tempo_map[1].tempo.num_beats, Rational::from(60));
+        assert_eq!(score.tempo_map[2].ramp.unwrap().target, Rational::from(45));
+        assert_eq!(
+            ms(&score)[..2],
+            [Rational::from(1000), Rational::from(1000)]
+        );
+
+        let mut score = Score::new(scan(s.to_string()).unwrap()).unwrap();
+        score.offset_tempo(-20.0).unwrap();
+        // relative tempo changes keep their proportion to the tempo before
+        let tempos: Vec<Rational> = score.tempo_map.iter().map(|m| m.tempo.num_beats).collect();
+        assert_eq!(
+            tempos,
+            vec![
+                Rational::from(100),
+                Rational::from(100),
+                Rational::from(40),
+                Rational::from(70)
+            ]
+        );
+        assert_eq!(score.tempo_map[2].ramp.unwrap().target, Rational::from(70));
+        assert_eq!(ms(&score)[..2], [Rational::from(600), Rational::from(600)]);
+        assert_eq!(
+            score.offset_tempo(-40.0),
+            Err(TempoError::NotPositive(Rational::from(40)))
+        );
+        assert_eq!(score.tempo_map[0].tempo.num_beats, Rational::from(100));
+        let data = [
+            (-99.9999999999, TempoError::NotPositive(Rational::from(100))),
+            (1e20, TempoError::TooFast(Rational::from(100))),
+            (f64::NAN, TempoError::NotPositive(Rational::from(100))),
+        ];
+        for (offset, error) in data {
+            assert_eq!(score.offset_tempo(offset), Err(error), "{offset}");
+        }
+        let tiny = Rational::new(1, u64::MAX - 1);
+        assert_eq!(
+            score.scale_tempo(tiny),
+            Err(TempoError::NotPositive(Rational::from(100)))
+        );
+        assert_eq!(score.tempo_map[0].tempo.num_beats, Rational::from(100));
+
+        // a relative tempo change at the start follows from the default tempo
+        let data = [
+            ("q = q. | q. |", 80),
+            ("h = q | q |", 40),
+            ("q ~> 180 @1 | q |", 80),
+        ];
+        for (s, num_beats) in data {
+            let mut score = Score::new(scan(s.to_string()).unwrap()).unwrap();
+            score.offset_tempo(-40.0).unwrap();
+            let tempo = score.tempo_map[0].tempo.num_beats;
+            assert_eq!(tempo, Rational::from(num_beats), "{s}");
+        }
+
+        // repeats restart in the tempo of their first pass
+        let data = [
+            ("q = 60 | w |: q | q = 120 q :|", 3),
+            ("q = 60 | w | q | q = 120 q |%%2", 3),
+            ("q = 60 | q q = 120 q |%2", 1),
+        ];
+        for (s, repeat) in data {
+            let mut score = Score::new(scan(s.to_string()).unwrap()).unwrap();
+            score.offset_tempo(10.0).unwrap();
+            assert_eq!(
+                score.bars[repeat].durations[0].ms,
+                Rational::new(6000, 7),
+                "{s}"
+            );
+        }
+    }
+
+    #[test]
+    fn repeated_tempo_changes() {
+        let toks = scan("q = 60 | q q=120 q |%3 q |".to_string()).unwrap();
+        let score = Score::new(toks).unwrap();
+        for bar in score.bars.iter().take(3) {
+            let ms: Vec<Rational> = bar.durations.iter().map(|d| d.ms).collect();
+            assert_eq!(ms, vec![Rational::from(1000), Rational::from(500)]);
+        }
+        assert_eq!(score.bars[3].durations[0].ms, Rational::from(500));
+
+        // ramps longer than the repeated bar carry on through the repeats
+        let toks = scan("q = 60 ~> 120 @2 | q q q q |%2 q |".to_string()).unwrap();
+        let score = Score::new(toks).unwrap();
+        assert_eq!(score.tempo_map.len(), 1);
+        assert_eq!(score.bars[2].durations[0].ms, Rational::from(500));
+        let toks = scan("q = 60 ~> 120 @1 | q q q q |%2 q |".to_string()).unwrap();
+        let score = Score::new(toks).unwrap();
+        assert_eq!(score.tempo_map.len(), 2);
+        assert_eq!(score.bars[1].durations[0].ms, score.bars[0].durations[0].ms);
+        let toks = scan("q = 60 ~> 120 @3 | q q=90 q |%2 q |".to_string()).unwrap();
+        assert!(Score::new(toks).is_ok());
+        // a ramp that ends inside the repeated bar is played again
+        let toks = scan("| q = 60 ~> 120 q q = 120 q |%2".to_string()).unwrap();
+        let score = Score::new(toks).unwrap();
+        assert_eq!(score.bars[0].durations[1].ms, score.bars[1].durations[1].ms);
+    }
+
+    #[test]
+    fn repeated_ramps_from_the_end_of_a_bar() {
+        let data = [
+            ("| q q = 60 ~> 120 @1 |: q :|", 1),
+            ("| q q = 60 ~> 120 @1 | q |%2", 1),
+            ("| q q = 60 ~> 120 @2 | q | q |%%2", 2),
+        ];
+        for (s, repeated) in data {
+            let score = Score::new(scan(s.to_string()).unwrap()).unwrap();
+            for bar in 1..=repeated {
+                assert_eq!(
+                    score.bars[bar].durations[0].ms,
+                    score.bars[bar + repeated].durations[0].ms,
+                    "{s}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn invalid_ramps() {
+        let data = [
+            "q = 100 ~>",
+            "q = 100 ~> q",
+            "q = 100 ~> 140 @",
+            "q = 100 ~> 280/2",
+            "q = 100 ~> (280/2",
+            "q = q. ~> 140",
+            "| ~> 140 |",
+            "| q @ 2 |",
+        ];
+        for d in data.iter() {
+            let toks = scan(d.to_string()).unwrap();
+            assert!(Score::new(toks).is_err(), "{d}");
+        }
+    }
+
+    #[test]
+    fn sections() {
+        let toks = scan("[A] | q q | [B] #time 2/4 q q |%2 [Coda] | h |".to_string()).unwrap();
+        let score = Score::new(toks).unwrap();
+        let sections: Vec<Option<&str>> = score.bars.iter().map(|b| b.section.as_deref()).collect();
+        assert_eq!(sections, vec![Some("A"), Some("B"), None, Some("Coda")]);
+
+        // outside of repeats, numbers are rehearsal marks rather than endings
+        let data = [
+            ("| q | [12] q |", vec![None, Some("12")]),
+            ("[1] | q | [2] q |", vec![Some("1"), Some("2")]),
+            (
+                "|: q | [1] q :| [2] q | [3] q |",
+                vec![None, None, None, None, Some("3")],
+            ),
+            ("|: q :| q | [2] q |", vec![None, None, None, Some("2")]),
+        ];
+        for (s, expected) in data {
+            let score = Score::new(scan(s.to_string()).unwrap()).unwrap();
+            let sections: Vec<Option<&str>> =
+                score.bars.iter().map(|b| b.section.as_deref()).collect();
+            assert_eq!(sections, expected, "{s}");
+        }
+
+        // repeated bars are part of the section they are played in
+        let data = [
+            "| q | [A] |: q | q :| [B] | q |",
+            "| q | [A] | q | q |%%2 [B] | q |",
+        ];
+        for d in data.iter() {
+            let score = Score::new(scan(d.to_string()).unwrap()).unwrap();
+            let names: Vec<&str> = score
+                .bars
+                .iter()
+                .filter_map(|b| b.section.as_deref())
+                .collect();
+            assert_eq!(names, vec!["A", "B"], "{d}");
+            assert_eq!(score.section_bars("A"), Some(2..=5), "{d}");
+            assert_eq!(score.section_bars("B"), Some(6..=6), "{d}");
+        }
+
+        let data = ["| q [A] q |", "| q | [A]", "| q | [A] |"];
+        for d in data.iter() {
+            let toks = scan(d.to_string()).unwrap();
+            assert!(
+                matches!(
+                    Score::new(toks),
+                    Err(MetrumError::ParseError(ParseError::SectionPlacement, _))
+                ),
+                "{d}"
+            );
+        }
+    }
+
+    #[test]
+    fn repeat_signs() {
+        let lengths =
+            |score: &Score| -> Vec<Rational> { score.bars.iter().map(|b| b.length()).collect() };
+        let data = [
+            ("|: q | h :|", vec![(1, 4), (1, 2), (1, 4), (1, 2)]),
+            (
+                "| w |: q | h :| e |",
+                vec![(1, 1), (1, 4), (1, 2), (1, 4), (1, 2), (1, 8)],
+            ),
+            (
+                "|: q | [1] h :| [2] e | s |",
+                vec![(1, 4), (1, 2), (1, 4), (1, 8), (1, 16)],
+            ),
+            ("|: q :|: h :|", vec![(1, 4), (1, 4), (1, 2), (1, 2)]),
+            ("|: q |%2 :|", vec![(1, 4); 4]),
+        ];
+        for (s, expected) in data.iter() {
+            let score = Score::new(scan(s.to_string()).unwrap()).unwrap();
+            let expected: Vec<Rational> = expected
+                .iter()
+                .map(|(n, d)| Rational::new(*n, *d))
+                .collect();
+            assert_eq!(lengths(&score), expected, "{s}");
+        }
+
+        // the second time through starts in the tempo the first one started in
+        let ms = |s: &str| -> Vec<Rational> {
+            let score = Score::new(scan(s.to_string()).unwrap()).unwrap();
+            score.bars.iter().map(|b| b.durations[0].ms).collect()
+        };
+        assert_eq!(
+            ms("q = 60 |: q | q = 120 q | [1] q = 90 q :| [2] q |"),
+            vec![
+                Rational::from(1000),
+                Rational::from(500),
+                Rational::new(2000, 3),
+                Rational::from(1000),
+                Rational::from(500),
+                Rational::from(500)
+            ]
+        );
+        // a ramp inside the repeat is played again
+        let ramp = ms("q = 60 |: q = 60 ~> 120 @1 | q q q q | q q q q :|");
+        assert_eq!(ramp[..2], ramp[2..]);
+        let ramp = ms("|: q = 60 ~> 120 | q q q q | q = 120 q q q q :|");
+        assert_eq!(ramp[..2], ramp[2..]);
+    }
+
+    #[test]
+    fn group_repeats() {
+        let lengths = |s: &str| -> Vec<Rational> {
+            let score = Score::new(scan(s.to_string()).unwrap()).unwrap();
+            score.bars.iter().map(|b| b.length()).collect()
+        };
+        let (q, h, w) = (Rational::new(1, 4), Rational::new(1, 2), Rational::from(1));
+        let data = [
+            ("| q | h |%%2", vec![q, h, q, h]),
+            ("| w | q | h |%%3", vec![w, q, h, q, h, q, h]),
+            ("| q | h | w |%[3]x2 q |", vec![q, h, w, q, h, w, q]),
+            ("| q | h |%[1]x3", vec![q, h, h, h]),
+        ];
+        for (s, expected) in data.iter() {
+            assert_eq!(lengths(s), *expected, "{s}");
+        }
+
+        // tempo changes inside the group are played again
+        let s = "q = 60 | q | q = 120 q |%%2";
+        let score = Score::new(scan(s.to_string()).unwrap()).unwrap();
+        let ms: Vec<Rational> = score.bars.iter().map(|b| b.durations[0].ms).collect();
+        assert_eq!(ms, [1000, 500, 1000, 500].map(Rational::from).to_vec());
+        // the copies belong to the section of the repeated bars
+        let s = "[A] | q | q |%%2";
+        let score = Score::new(scan(s.to_string()).unwrap()).unwrap();
+        let sections: Vec<bool> = score.bars.iter().map(|b| b.section.is_some()).collect();
+        assert_eq!(sections, vec![true, false, false, false]);
+
+        let data = ["| q |%%2", "| q | q |%[3]x2"];
+        for d in data.iter() {
+            let toks = scan(d.to_string()).unwrap();
+            assert!(
+                matches!(
+                    Score::new(toks),
+                    Err(MetrumError::ParseError(ParseError::NothingToRepeat, _))
+                ),
+                "{d}"
+            );
+        }
+    }
+
+    #[test]
+    fn invalid_repeat_signs() {
+        let data = [
+            ("| q | q :|", ParseError::UnopenedRepeat, 9),
+            ("|: q | q |", ParseError::UnclosedRepeat, 1),
+            ("|: q |: q :| :|", ParseError::NestedRepeat, 6),
+            ("| q |: :|", ParseError::EmptyRepeat, 5),
+            ("| q |: | :|", ParseError::EmptyRepeat, 5),
+            ("|: q :|: :|", ParseError::EmptyRepeat, 6),
+            ("|: q [1] q :|", ParseError::FirstEnding, 6),
+            ("|: [1] q | [1] q :|", ParseError::FirstEnding, 12),
+            ("|: q :| [1] q |", ParseError::FirstEnding, 9),
+            ("|: q :| [2] q |", ParseError::SecondEnding, 9),
+            ("|: q | [2] q :|", ParseError::SecondEnding, 8),
+            ("|: q :| q %2", ParseError::BarRepeat, 11),
+        ];
+        for (s, error, column) in data.iter() {
+            match Score::new(scan(s.to_string()).unwrap()) {
+                Err(MetrumError::ParseError(e, span)) => {
+                    assert_eq!(e, *error, "{s}");
+                    assert_eq!(span.column, *column, "{s}");
+                }
+                _ => panic!("expected a parse error for {s}"),
+            }
+        }
+    }
+
+    #[test]
+    fn count_in() {
+        let data = [
+            ("#count-in 2 | q q q q |", 2, vec![Rational::from(500); 4]),
+            (
+                "#count-in 1 q. = 60 | q. q. |",
+                1,
+                vec![Rational::from(1000); 2],
+            ),
+            (
+                "#count-in 1 | h e e e |",
+                1,
+                vec![
+                    Rational::from(500),
+                    Rational::from(500),
+                    Rational::from(500),
+                    Rational::from(250),
+                ],
+            ),
+            (
+                "#count-in 1 q = 60 #time 3/4 | h. |",
+                1,
+                vec![Rational::from(1000); 3],
+            ),
+            (
+                "#count-in 1 q = 60 #time 6/8 | h. |",
+                1,
+                vec![Rational::from(1500); 2],
+            ),
+            (
+                "#count-in 1 e = 120 #time 7/8 (2+2+3) | e e e e e e e |",
+                1,
+                vec![
+                    Rational::from(1000),
+                    Rational::from(1000),
+                    Rational::from(1500),
+                ],
+            ),
+            ("| q q q q |", 0, vec![]),
+            ("#count-in 0 | q q q q |", 0, vec![]),
+        ];
+        for (d, num_bars, ms) in data.iter() {
+            let score = Score::new(scan(d.to_string()).unwrap()).unwrap();
+            let bars = score.count_in_bars();
+            assert_eq!(bars.len(), *num_bars, "{d}");
+            for bar in bars.iter() {
+                let output: Vec<Rational> = bar.durations.iter().map(|d| d.ms).collect();
+                assert_eq!(output, *ms, "{d}");
+                assert_eq!(bar.durations[0].accent, Accent::Downbeat);
+            }
+        }
+    }
+
+    #[test]
+    fn misplaced_count_in() {
+        let data = ["#count-in", "#count-in q", "| q | #count-in 2 | q |"];
+        for d in data.iter() {
+            let toks = scan(d.to_string()).unwrap();
+            assert!(Score::new(toks).is_err(), "{d}");
+        }
+    }
+
+    #[test]
+    fn subdivisions() {
+        let data = [
+            ("#subdivide 2 | q q |", vec![(1, 8), (3, 8)]),
+            (
+                "#subdivide 3 | h |",
+                vec![(1, 12), (2, 12), (3, 12), (4, 12), (5, 12)],
+            ),
+            ("#subdivide 2 | q qr |", vec![(1, 8), (2, 8), (3, 8)]),
+            ("| q | #subdivide 2 | q | #subdivide | q |", vec![(3, 8)]),
+            ("#subdivide 2 | q q = q. q. |", vec![(1, 8), (7, 16)]),
+            ("| q q |", vec![]),
+        ];
+        for (d, positions) in data.iter() {
+            let score = Score::new(scan(d.to_string()).unwrap()).unwrap();
+            let expected: Vec<Rational> = positions
+                .iter()
+                .map(|(n, d)| Rational::new(*n, *d))
+                .collect();
+            assert_eq!(score.subdivision_positions(), expected, "{d}");
+        }
+    }
+
+    #[test]
+    fn misplaced_subdivisions() {
+        let toks = scan("| q #subdivide 2 q |".to_string()).unwrap();
+        assert!(Score::new(toks).is_err());
+    }
+
+    #[test]
+    fn misplaced_rests() {
+        let data = ["| r |", "| q | r", "q=r", "| rx2 |"];
+        for d in data.iter() {
+            let toks = scan(d.to_string()).unwrap();
+            assert!(Score::new(toks).is_err(), "{d}");
+        }
+    }
+
     extern crate test_generator;
     use test_generator::test_resources;
 
@@ -0,0 +1,298 @@
+use std::ops::RangeInclusive;
+
+use crate::error::{SliceError, TempoError};
+use crate::score::{Bar, Ramp, Score, TempoMark};
+use crate::units::Rational;
+
+/// The length of a run of bars in whole notes
+fn length_of(bars: &[Bar]) -> Rational {
+    bars.iter()
+        .fold(Rational::zero(), |acc, bar| acc + bar.length())
+}
+
+/// Plays scores one after another, with the count-in of the first one
+fn join(scores: &[Score]) -> Score {
+    let mut bars = Vec::new();
+    let mut tempo_map = Vec::new();
+    let mut offset = Rational::zero();
+    for score in scores.iter() {
+        bars.extend(score.bars.iter().cloned());
+        tempo_map.extend(score.tempo_map.iter().map(|mark| TempoMark {
+            position: mark.position + offset,
+            ramp: mark.ramp.map(|ramp| Ramp {
+                end: ramp.end + offset,
+                ..ramp
+            }),
+            ..*mark
+        }));
+        offset += score.length();
+    }
+    let mut joined = Score {
+        bars,
+        tempo_map,
+        count_in: scores[0].count_in,
+    };
+    joined.retime();
+    joined
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+/// How the tempo of a passage increases from pass to pass in [Score::practice], in percent of the
+/// tempo of the score
+pub struct Practice {
+    /// The tempo of the first pass
+    pub start: u16,
+    /// How much faster every pass is than the one before
+    pub step: u16,
+}
+
+impl Practice {
+    /// The tempo of a pass, counted from 0. The tempo stops increasing at the tempo of the score,
+    /// unless the first pass is already faster.
+    pub fn percent(&self, pass: u16) -> u16 {
+        match self.start >= 100 {
+            true => self.start,
+            false => self
+                .start
+                .saturating_add(self.step.saturating_mul(pass))
+                .min(100),
+        }
+    }
+
+    /// The number of passes it takes to reach the tempo of the score
+    pub fn passes(&self) -> u16 {
+        match self.start >= 100 || self.step == 0 {
+            true => 1,
+            false => (100 - self.start).div_ceil(self.step) + 1,
+        }
+    }
+}
+
+/// Moves a tempo mark into a slice of the score from `start` to `end` in whole notes. A mark from
+/// before the slice starts it in the tempo the mark reached by then and a ramp that lasts past the
+/// end of the slice is cut short at the tempo it reached there.
+fn clip(mark: &TempoMark, start: Rational, end: Rational) -> TempoMark {
+    let position = mark.position.max(start);
+    let ramp = mark.ramp.filter(|ramp| ramp.end > position).map(|ramp| {
+        let ramp_end = ramp.end.min(end);
+        Ramp {
+            target: mark.tempo_at(ramp_end - mark.position).num_beats,
+            end: ramp_end - start,
+            ..ramp
+        }
+    });
+    TempoMark {
+        position: position - start,
+        tempo: mark.tempo_at(position - mark.position),
+        ramp,
+        relative: mark.relative && mark.position >= start,
+    }
+}
+
+impl Score {
+    /// Returns the bars of the section with the given name, counted from 1. A section lasts until
+    /// the next rehearsal mark or the end of the score. If several sections share the name, the
+    /// first one is returned.
+    pub fn section_bars(&self, name: &str) -> Option<RangeInclusive<usize>> {
+        let first = self
+            .bars
+            .iter()
+            .position(|bar| bar.section.as_deref() == Some(name))?;
+        let rest = self.bars[first + 1..]
+            .iter()
+            .take_while(|bar| bar.section.is_none())
+            .count();
+        Some(first + 1..=first + 1 + rest)
+    }
+
+    /// Cuts a range of bars, counted from 1, out of the score. The slice starts in the tempo in
+    /// effect at its first bar, even if it was set earlier or is in the middle of a ramp, and keeps
+    /// the count-in of the score.
+    pub fn slice(&self, bars: RangeInclusive<usize>) -> Result<Score, SliceError> {
+        let (first, last) = (*bars.start(), *bars.end());
+        if first == 0 || first > last || last > self.bars.len() {
+            return Err(SliceError::Bars {
+                first,
+                last,
+                bars: self.bars.len(),
+            });
+        }
+        let start = length_of(&self.bars[..first - 1]);
+        let end = start + length_of(&self.bars[first - 1..last]);
+        let mut tempo_map = vec![clip(self.tempo_mark_at(start), start, end)];
+        tempo_map.extend(
+            self.tempo_map
+                .iter()
+                .filter(|mark| mark.position > start && mark.position < end)
+                .map(|mark| clip(mark, start, end)),
+        );
+        let mut score = Score {
+            bars: self.bars[first - 1..last].to_vec(),
+            tempo_map,
+            count_in: self.count_in,
+        };
+        score.retime();
+        Ok(score)
+    }
+
+    /// Cuts the section with the given name out of the score, see [Score::section_bars] and
+    /// [Score::slice]
+    pub fn section(&self, name: &str) -> Result<Score, SliceError> {
+        let bars = self
+            .section_bars(name)
+            .ok_or_else(|| SliceError::UnknownSection(name.to_string()))?;
+        self.slice(bars)
+    }
+
+    /// Plays the score a number of times in a row, but at least once. Every time starts over in
+    /// the tempo the score starts in and the count-in is only played before the first time.
+    pub fn looped(&self, times: u16) -> Score {
+        join(&vec![self.clone(); times.max(1) as usize])
+    }
+
+    /// Plays the score a number of times in a row, but at least once, speeding up from pass to
+    /// pass as given by `practice`. Every pass starts over in the tempo the score starts in, scaled
+    /// to the tempo of the pass, and the count-in is played at the tempo of the first pass. Fails
+    /// if a pass would be too slow to be represented.
+    pub fn practice(&self, practice: &Practice, passes: u16) -> Result<Score, TempoError> {
+        let passes = (0..passes.max(1))
+            .map(|pass| {
+                let mut score = self.clone();
+                score.scale_tempo(Rational::new(practice.percent(pass) as u64, 100))?;
+                Ok(score)
+            })
+            .collect::<Result<Vec<Score>, TempoError>>()?;
+        Ok(join(&passes))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Practice;
+    use crate::error::SliceError;
+    use crate::score::Score;
+    use crate::{scanner::scan, units::Rational};
+
+    fn score(s: &str) -> Score {
+        Score::new(scan(s.to_string()).unwrap()).unwrap()
+    }
+
+    /// The length of a score in *milliseconds*
+    fn ms(score: &Score) -> f64 {
+        score.time_at(score.length()).as_f64()
+    }
+
+    #[test]
+    fn slices() {
+        let s = score("q = 60 | q q q q | q = 120 q q q q | q q q q | q = q. q |");
+        // the slice starts in the tempo set in bar 2
+        let slice = s.slice(3..=4).unwrap();
+        assert_eq!(slice.bars.len(), 2);
+        assert_eq!(slice.tempo_map.len(), 2);
+        assert_eq!(slice.tempo_map[0].position, Rational::zero());
+        assert_eq!(slice.tempo_map[0].tempo.num_beats, Rational::from(120));
+        assert_eq!(slice.bars[0].durations[0].ms, Rational::from(500));
+        assert_eq!(slice.bars[1].durations[0].ms, Rational::new(1000, 3));
+        let slice = s.slice(1..=1).unwrap();
+        assert_eq!(slice.tempo_map.len(), 1);
+        assert_eq!(slice.bars[0].durations[0].ms, Rational::from(1000));
+
+        let data = [(0, 1), (2, 1), (1, 5)];
+        for (first, last) in data.iter() {
+            assert_eq!(
+                s.slice(*first..=*last).unwrap_err(),
+                SliceError::Bars {
+                    first: *first,
+                    last: *last,
+                    bars: 4
+                }
+            );
+        }
+    }
+
+    #[test]
+    fn ramps() {
+        let s = score("q = 60 ~> 120 @2 | q q q q | q q q q | q q |");
+        // a slice from the middle of a ramp keeps speeding up
+        let slice = s.slice(2..=3).unwrap();
+        let expected = ms(&s) - s.time_at(Rational::from(1)).as_f64();
+        assert!((ms(&slice) - expected).abs() < 0.001);
+        // a ramp that lasts past the end of the slice is cut short
+        let slice = s.slice(1..=1).unwrap();
+        let expected = s.time_at(Rational::from(1)).as_f64();
+        assert!((ms(&slice) - expected).abs() < 0.001);
+        assert!(slice.tempo_map[0].ramp.unwrap().target < Rational::from(120));
+    }
+
+    #[test]
+    fn sections() {
+        let s = score("| q | [A] | q | q | [B] | q | [A] | q |");
+        assert_eq!(s.section_bars("A"), Some(2..=3));
+        assert_eq!(s.section_bars("B"), Some(4..=4));
+        assert_eq!(s.section_bars("C"), None);
+        assert_eq!(s.section("A").unwrap().bars.len(), 2);
+        assert_eq!(
+            s.section("C").unwrap_err(),
+            SliceError::UnknownSection("C".to_string())
+        );
+    }
+
+    #[test]
+    fn loops() {
+        let s = score("#count-in 1 q = 60 | q q | q = 120 q q |");
+        let looped = s.looped(3);
+        assert_eq!(looped.bars.len(), 6);
+        assert_eq!(looped.count_in, 1);
+        // every time starts over in the opening tempo
+        assert_eq!(looped.bars[2].durations[0].ms, Rational::from(1000));
+        assert_eq!(looped.bars[3].durations[0].ms, Rational::from(500));
+        assert_eq!(ms(&looped), 3.0 * ms(&s));
+        assert_eq!(s.looped(0).bars.len(), 2);
+    }
+
+    #[test]
+    fn practice() {
+        let data = [
+            ((70, 5), vec![70, 75, 80, 85, 90, 95, 100, 100], 7),
+            ((80, 15), vec![80, 95, 100], 3),
+            ((50, 0), vec![50, 50], 1),
+            ((110, 5), vec![110, 110], 1),
+        ];
+        for ((start, step), percents, passes) in data.iter() {
+            let practice = Practice {
+                start: *start,
+                step: *step,
+            };
+            let actual: Vec<u16> = (0..percents.len() as u16)
+                .map(|pass| practice.percent(pass))
+                .collect();
+            assert_eq!(actual, *percents);
+            assert_eq!(practice.passes(), *passes);
+        }
+
+        let s = score("#count-in 1 q = 100 | q q | q = 120 q q |");
+        let practice = Practice {
+            start: 50,
+            step: 25,
+        };
+        let passes = s.practice(&practice, 3).unwrap();
+        assert_eq!(passes.bars.len(), 6);
+        let ms: Vec<Rational> = passes.bars.iter().map(|b| b.durations[0].ms).collect();
+        assert_eq!(
+            ms,
+            vec![
+                Rational::from(1200),
+                Rational::from(1000),
+                Rational::from(800),
+                Rational::new(2000, 3),
+                Rational::from(600),
+                Rational::from(500)
+            ]
+        );
+        // the count-in is played at the tempo of the first pass
+        assert_eq!(
+            passes.count_in_bars()[0].durations[0].ms,
+            Rational::from(1200)
+        );
+    }
+}
@@ -0,0 +1,256 @@
+use std::f32::consts::TAU;
+
+use crate::writer::{GHOST_GAIN, SECONDARY_GAIN, SUBDIVISION_GAIN};
+
+/// Level of the loudest sample of a click at full gain, a little below full scale
+const PEAK: f32 = 0.8 * i16::MAX as f32;
+
+/// The envelope of a click decays to a thousandth (-60 dB) after this many decay times, where
+/// the click ends
+const LENGTH_IN_DECAYS: f32 = 6.9;
+
+/// Interval between the downbeat and the other clicks of [SynthClicks::new], a fifth
+const DOWNBEAT_INTERVAL: f32 = 1.5;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// The timbre of a synthesized click
+pub enum Voice {
+    /// A pure sine tone
+    Sine,
+    /// Noise ringing through a resonant filter tuned to the pitch
+    Woodblock,
+    /// Two detuned square waves, like a drum machine cowbell
+    Cowbell,
+    /// A short tone at the pitch with a burst of bright noise on top
+    Rimshot,
+}
+
+impl Voice {
+    /// The pitch in Hz the voice sounds best at
+    pub fn default_pitch(&self) -> f32 {
+        match self {
+            Voice::Sine => 1000.0,
+            Voice::Woodblock => 800.0,
+            Voice::Cowbell => 560.0,
+            Voice::Rimshot => 1700.0,
+        }
+    }
+
+    /// The decay time in seconds that fits the voice
+    pub fn default_decay(&self) -> f32 {
+        match self {
+            Voice::Sine => 0.03,
+            Voice::Woodblock => 0.04,
+            Voice::Cowbell => 0.12,
+            Voice::Rimshot => 0.025,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+/// A click that is synthesized instead of played back from a sample. The decay is the time in
+/// seconds in which the click fades to about a third of its level and the gain scales the click
+/// from silent (0.0) to full level (1.0).
+pub struct SynthClick {
+    pub voice: Voice,
+    pub pitch: f32,
+    pub decay: f32,
+    pub gain: f32,
+}
+
+impl SynthClick {
+    /// Creates a click of the given voice at its default pitch and decay and at full level
+    pub fn new(voice: Voice) -> Self {
+        SynthClick {
+            voice,
+            pitch: voice.default_pitch(),
+            decay: voice.default_decay(),
+            gain: 1.0,
+        }
+    }
+
+    /// Renders the click at the given sample rate
+    pub fn render(&self, sample_rate: u32) -> Vec<i16> {
+        let rate = sample_rate as f32;
+        let len = (self.decay.max(0.0) * LENGTH_IN_DECAYS * rate) as usize;
+        let envelope = |i: usize| (-(i as f32) / (self.decay * rate)).exp();
+        let phase = |i: usize, pitch: f32| i as f32 * pitch / rate;
+        let mut noise = Noise::new();
+        let wave: Vec<f32> = match self.voice {
+            Voice::Sine => (0..len)
+                .map(|i| (TAU * phase(i, self.pitch)).sin() * envelope(i))
+                .collect(),
+            Voice::Woodblock => {
+                let mut resonator = Resonator::new(self.pitch, rate);
+                (0..len)
+                    .map(|i| resonator.next(noise.next() * envelope(i * 4)) * envelope(i))
+                    .collect()
+            }
+            Voice::Cowbell => {
+                let square = |x: f32| if x.fract() < 0.5 { 1.0 } else { -1.0 };
+                let mut lowpass = 0.0;
+                (0..len)
+                    .map(|i| {
+                        let tone =
+                            square(phase(i, self.pitch)) + square(phase(i, self.pitch * 1.4823));
+                        lowpass += (tone - lowpass) * 0.3;
+                        // the attack fades quickly before the tone rings out
+                        lowpass * (envelope(i) + envelope(i * 8)) / 2.0
+                    })
+                    .collect()
+            }
+            Voice::Rimshot => {
+                let mut previous = 0.0;
+                (0..len)
+                    .map(|i| {
+                        let sample = noise.next();
+                        // a first order highpass keeps the noise bright
+                        let bright = sample - previous;
+                        previous = sample;
+                        let tone = (TAU * phase(i, self.pitch)).sin();
+                        (tone * 0.6 + bright * 0.4 * envelope(i * 3)) * envelope(i)
+                    })
+                    .collect()
+            }
+        };
+        let peak = wave.iter().fold(0.0f32, |peak, s| peak.max(s.abs()));
+        let scale = match peak > 0.0 {
+            true => PEAK * self.gain.clamp(0.0, 1.0) / peak,
+            false => 0.0,
+        };
+        wave.iter().map(|s| (s * scale) as i16).collect()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+/// The synthesized click of every accent level and of the subdivisions
+pub struct SynthClicks {
+    pub downbeat: SynthClick,
+    pub secondary: SynthClick,
+    pub unaccented: SynthClick,
+    pub ghost: SynthClick,
+    pub subdivision: SynthClick,
+}
+
+impl SynthClicks {
+    /// Creates the clicks of every level from a single voice at the given pitch. The downbeat and
+    /// the secondary beats sound a fifth higher and the levels are turned down like the embedded
+    /// clicks.
+    pub fn new(voice: Voice, pitch: f32) -> Self {
+        let click = SynthClick {
+            pitch,
+            ..SynthClick::new(voice)
+        };
+        let high = SynthClick {
+            pitch: pitch * DOWNBEAT_INTERVAL,
+            ..click
+        };
+        SynthClicks {
+            downbeat: high,
+            secondary: SynthClick {
+                gain: SECONDARY_GAIN,
+                ..high
+            },
+            unaccented: click,
+            ghost: SynthClick {
+                gain: GHOST_GAIN,
+                ..click
+            },
+            subdivision: SynthClick {
+                gain: SUBDIVISION_GAIN,
+                ..click
+            },
+        }
+    }
+}
+
+/// A deterministic noise source, so that the same click always sounds the same
+struct Noise(u32);
+
+impl Noise {
+    fn new() -> Self {
+        Noise(0x9E37_79B9)
+    }
+
+    /// Returns the next sample, between -1.0 and 1.0
+    fn next(&mut self) -> f32 {
+        // xorshift32
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 17;
+        self.0 ^= self.0 << 5;
+        self.0 as f32 / u32::MAX as f32 * 2.0 - 1.0
+    }
+}
+
+/// A two pole filter that rings at a frequency
+struct Resonator {
+    a1: f32,
+    a2: f32,
+    y1: f32,
+    y2: f32,
+}
+
+impl Resonator {
+    fn new(frequency: f32, sample_rate: f32) -> Self {
+        let r = 0.995;
+        Resonator {
+            a1: 2.0 * r * (TAU * frequency / sample_rate).cos(),
+            a2: -r * r,
+            y1: 0.0,
+            y2: 0.0,
+        }
+    }
+
+    fn next(&mut self, x: f32) -> f32 {
+        let y = x + self.a1 * self.y1 + self.a2 * self.y2;
+        (self.y2, self.y1) = (self.y1, y);
+        y
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{SynthClick, SynthClicks, Voice, LENGTH_IN_DECAYS, PEAK};
+
+    #[test]
+    fn clicks() {
+        let voices = [
+            Voice::Sine,
+            Voice::Woodblock,
+            Voice::Cowbell,
+            Voice::Rimshot,
+        ];
+        for voice in voices.iter() {
+            let click = SynthClick::new(*voice);
+            let samples = click.render(44100);
+            assert_eq!(
+                samples.len(),
+                (voice.default_decay() * LENGTH_IN_DECAYS * 44100.0) as usize
+            );
+            let peak = samples.iter().map(|s| s.unsigned_abs()).max().unwrap();
+            assert_eq!(peak, PEAK as u16, "{voice:?}");
+            assert_eq!(samples, click.render(44100), "{voice:?}");
+            // the click fades out
+            let tail = samples.iter().rev().take(100).map(|s| s.unsigned_abs());
+            assert!(tail.max().unwrap() < peak / 100, "{voice:?}");
+        }
+    }
+
+    #[test]
+    fn levels() {
+        let clicks = SynthClicks::new(Voice::Sine, 1000.0);
+        assert_eq!(clicks.downbeat.pitch, 1500.0);
+        let peak = |click: &SynthClick| {
+            let samples = click.render(48000);
+            samples.iter().map(|s| s.unsigned_abs()).max().unwrap()
+        };
+        assert!(peak(&clicks.downbeat) > peak(&clicks.secondary));
+        assert!(peak(&clicks.unaccented) > peak(&clicks.ghost));
+        assert!(peak(&clicks.ghost) > peak(&clicks.subdivision));
+        let silent = SynthClick {
+            gain: 0.0,
+            ..clicks.unaccented
+        };
+        assert!(silent.render(48000).iter().all(|s| *s == 0));
+    }
+}
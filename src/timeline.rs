@@ -0,0 +1,354 @@
+use std::fs;
+use std::io;
+
+use crate::score::{BarStart, Score};
+use crate::units::Rational;
+
+#[derive(Debug, Clone, PartialEq)]
+/// A named stretch of the click track, such as a bar. Times are in *milliseconds* from the start
+/// of the count-in.
+pub struct Label {
+    pub start: Rational,
+    pub end: Rational,
+    pub text: String,
+}
+
+/// Formats a number with up to `places` decimal places, leaving out trailing zeros
+fn decimal(n: f64, places: usize) -> String {
+    let formatted = format!("{n:.places$}");
+    match formatted.contains('.') {
+        true => formatted
+            .trim_end_matches('0')
+            .trim_end_matches('.')
+            .to_string(),
+        false => formatted,
+    }
+}
+
+/// Formats a time in *milliseconds* as seconds
+fn seconds(ms: Rational) -> String {
+    format!("{:.6}", ms.as_f64() / 1000.0)
+}
+
+/// Quotes a CSV field if it contains a separator, a quote or a line break
+fn csv_field(field: &str) -> String {
+    match field.contains([',', '"', '\n', '\r']) {
+        true => format!("\"{}\"", field.replace('"', "\"\"")),
+        false => field.to_string(),
+    }
+}
+
+/// Writes a string as a JSON string literal
+fn json_string(s: &str) -> String {
+    let mut json = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => json.push_str("\\\""),
+            '\\' => json.push_str("\\\\"),
+            '\n' => json.push_str("\\n"),
+            '\r' => json.push_str("\\r"),
+            '\t' => json.push_str("\\t"),
+            c if (c as u32) < 0x20 => json.push_str(&format!("\\u{:04x}", c as u32)),
+            c => json.push(c),
+        }
+    }
+    json.push('"');
+    json
+}
+
+/// The text of the label of a bar, its number or "Count-in" for the bars of the count-in
+fn bar_label(start: &BarStart) -> String {
+    match start.number {
+        Some(number) => number.to_string(),
+        None => "Count-in".to_string(),
+    }
+}
+
+impl Score {
+    /// Returns the start of every bar of the click track together with where it ends in
+    /// *milliseconds*
+    fn bar_spans(&self) -> Vec<(BarStart, Rational)> {
+        let (_, count_in_ms) = self.count_in_length();
+        let end = count_in_ms + self.time_at(self.length());
+        let starts = self.bar_starts();
+        let ends: Vec<Rational> = starts.iter().skip(1).map(|s| s.ms).chain([end]).collect();
+        starts.into_iter().zip(ends).collect()
+    }
+
+    /// Returns a label for every bar of the click track, from the count-in to the last bar
+    pub fn labels(&self) -> Vec<Label> {
+        self.bar_spans()
+            .into_iter()
+            .map(|(start, end)| Label {
+                text: bar_label(&start),
+                start: start.ms,
+                end,
+            })
+            .collect()
+    }
+
+    /// Returns a label for every section of the score, named after its rehearsal mark and lasting
+    /// until the next rehearsal mark or the end of the score
+    pub fn sections(&self) -> Vec<Label> {
+        let mut sections: Vec<Label> = Vec::new();
+        for (start, end) in self.bar_spans() {
+            if let Some(name) = start.section {
+                sections.push(Label {
+                    start: start.ms,
+                    end,
+                    text: name,
+                });
+            } else if let Some(last) = sections.last_mut() {
+                last.end = end;
+            }
+        }
+        sections
+    }
+
+    /// Creates an Audacity label track with a label spanning every bar and every section. Every
+    /// line holds the start and the end in seconds and the text of a label, separated by tabs.
+    pub fn audacity_labels(&self) -> String {
+        let mut labels = self.sections();
+        labels.extend(self.labels());
+        // the sort is stable, so a section comes before the bar it starts with
+        labels.sort_by_key(|l| l.start);
+        labels
+            .iter()
+            .map(|l| format!("{}\t{}\t{}\n", seconds(l.start), seconds(l.end), l.text))
+            .collect()
+    }
+
+    /// Creates a marker list that Reaper's region/marker manager can import, with a marker at the
+    /// start of every bar and a region for every section
+    pub fn reaper_markers_csv(&self) -> String {
+        let mut csv = String::from("#,Name,Start,End,Length\n");
+        for (i, label) in self.labels().iter().enumerate() {
+            csv.push_str(&format!(
+                "M{},{},{},,\n",
+                i + 1,
+                csv_field(&label.text),
+                seconds(label.start)
+            ));
+        }
+        for (i, section) in self.sections().iter().enumerate() {
+            csv.push_str(&format!(
+                "R{},{},{},{},{}\n",
+                i + 1,
+                csv_field(&section.text),
+                seconds(section.start),
+                seconds(section.end),
+                seconds(section.end - section.start)
+            ));
+        }
+        csv
+    }
+
+    /// Creates a JSON timeline with an object for every bar of the click track and for every
+    /// section. Times are in seconds, positions in whole notes and the bar number is `null` for the
+    /// count-in, as is the section of a bar without a rehearsal mark.
+    pub fn timeline_json(&self) -> String {
+        let bars: Vec<String> = self
+            .bar_spans()
+            .iter()
+            .map(|(start, end)| {
+                format!(
+                    "    {{\"bar\": {}, \"label\": {}, \"start\": {}, \"end\": {}, \"position\": \"{}\", \"tempo\": {}, \"beat\": \"{}\", \"meter\": \"{}/{}\", \"section\": {}}}",
+                    start.number.map(|n| n.to_string()).unwrap_or("null".to_string()),
+                    json_string(&bar_label(start)),
+                    seconds(start.ms),
+                    seconds(*end),
+                    start.position,
+                    decimal(start.tempo.num_beats.as_f64(), 3),
+                    start.tempo.beat,
+                    start.meter.top,
+                    start.meter.bottom,
+                    start.section.as_deref().map(json_string).unwrap_or("null".to_string()),
+                )
+            })
+            .collect();
+        let sections: Vec<String> = self
+            .sections()
+            .iter()
+            .map(|section| {
+                format!(
+                    "    {{\"label\": {}, \"start\": {}, \"end\": {}}}",
+                    json_string(&section.text),
+                    seconds(section.start),
+                    seconds(section.end),
+                )
+            })
+            .collect();
+        format!(
+            "{{\n  \"bars\": [\n{}\n  ],\n  \"sections\": [{}]\n}}\n",
+            bars.join(",\n"),
+            match sections.is_empty() {
+                true => String::new(),
+                false => format!("\n{}\n  ", sections.join(",\n")),
+            }
+        )
+    }
+
+    /// Writes the labels to a file, as JSON if the path ends in .json, as a Reaper marker list if
+    /// it ends in .csv and as an Audacity label track otherwise
+    pub fn write_labels(&self, path: &str) -> io::Result<()> {
+        let labels = match path {
+            p if p.ends_with(".json") => self.timeline_json(),
+            p if p.ends_with(".csv") => self.reaper_markers_csv(),
+            _ => self.audacity_labels(),
+        };
+        fs::write(path, labels)
+    }
+
+    /// Creates a tempo map in the CSV format with a row for every bar of the click track,
+    /// including the count-in. The columns are the bar number (empty for the count-in), the start
+    /// in seconds and in whole notes, the tempo in beats per minute, the beat as a fraction of a
+    /// whole note, the time signature with its beat groups and the rehearsal mark of the bar.
+    pub fn tempo_map_csv(&self) -> String {
+        let mut csv = String::from("bar,time,position,tempo,beat,meter,groups,section\n");
+        for start in self.bar_starts() {
+            let groups: Vec<String> = start.meter.groups.iter().map(|g| g.to_string()).collect();
+            csv.push_str(&format!(
+                "{},{},{},{},{},{}/{},{},{}\n",
+                start.number.map(|n| n.to_string()).unwrap_or_default(),
+                seconds(start.ms),
+                start.position,
+                decimal(start.tempo.num_beats.as_f64(), 3),
+                start.tempo.beat,
+                start.meter.top,
+                start.meter.bottom,
+                groups.join("+"),
+                csv_field(start.section.as_deref().unwrap_or_default()),
+            ));
+        }
+        csv
+    }
+
+    /// Writes the tempo map as a CSV file, see [Score::tempo_map_csv]
+    pub fn write_tempo_map_csv(&self, path: &str) -> io::Result<()> {
+        fs::write(path, self.tempo_map_csv())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{csv_field, decimal, json_string};
+    use crate::{scanner::scan, score::Score};
+
+    #[test]
+    fn decimals() {
+        let data = [
+            (120.0, "120"),
+            (92.5, "92.5"),
+            (60.0004, "60"),
+            (1.0 / 3.0, "0.333"),
+        ];
+        for (n, s) in data.iter() {
+            assert_eq!(decimal(*n, 3), *s);
+        }
+    }
+
+    #[test]
+    fn tempo_maps() {
+        let data = [
+            (
+                "| q q q q |%2",
+                "bar,time,position,tempo,beat,meter,groups,section\n\
+                 1,0.000000,0,120,1/4,4/4,2+2,\n\
+                 2,2.000000,1,120,1/4,4/4,2+2,\n",
+            ),
+            (
+                "#count-in 1 q. = 60 #time 6/8 | e e e e e e | q = 92.5 h. |",
+                "bar,time,position,tempo,beat,meter,groups,section\n\
+                 ,0.000000,0,60,3/8,6/8,3+3,\n\
+                 1,2.000000,3/4,60,3/8,6/8,3+3,\n\
+                 2,4.000000,3/2,92.5,1/4,6/8,3+3,\n",
+            ),
+            (
+                "q = 60 ~> 120 | q q q q | q q | h = 60 | h |",
+                "bar,time,position,tempo,beat,meter,groups,section\n\
+                 1,0.000000,0,60,1/4,4/4,2+2,\n\
+                 2,3.064954,1,100,1/4,2/4,2,\n\
+                 3,4.158883,3/2,60,1/2,2/4,2,\n",
+            ),
+        ];
+        for (s, csv) in data.iter() {
+            let score = Score::new(scan(s.to_string()).unwrap()).unwrap();
+            assert_eq!(score.tempo_map_csv(), *csv, "{s}");
+        }
+    }
+
+    #[test]
+    fn escaping() {
+        assert_eq!(csv_field("A"), "A");
+        assert_eq!(csv_field("A, B"), "\"A, B\"");
+        assert_eq!(csv_field("\"A\""), "\"\"\"A\"\"\"");
+        assert_eq!(json_string("A"), "\"A\"");
+        assert_eq!(json_string("\"A\"\\\n"), "\"\\\"A\\\"\\\\\\n\"");
+        assert_eq!(json_string("\u{1}"), "\"\\u0001\"");
+    }
+
+    #[test]
+    fn labels() {
+        let score = Score::new(scan("#count-in 1 | q q q q | h |".to_string()).unwrap()).unwrap();
+        assert_eq!(
+            score.audacity_labels(),
+            "0.000000\t2.000000\tCount-in\n\
+             2.000000\t4.000000\t1\n\
+             4.000000\t5.000000\t2\n"
+        );
+        assert_eq!(
+            score.reaper_markers_csv(),
+            "#,Name,Start,End,Length\n\
+             M1,Count-in,0.000000,,\n\
+             M2,1,2.000000,,\n\
+             M3,2,4.000000,,\n"
+        );
+        assert_eq!(
+            score.timeline_json(),
+            "{\n  \"bars\": [\n\
+             \x20   {\"bar\": null, \"label\": \"Count-in\", \"start\": 0.000000, \"end\": 2.000000, \"position\": \"0\", \"tempo\": 120, \"beat\": \"1/4\", \"meter\": \"4/4\", \"section\": null},\n\
+             \x20   {\"bar\": 1, \"label\": \"1\", \"start\": 2.000000, \"end\": 4.000000, \"position\": \"1\", \"tempo\": 120, \"beat\": \"1/4\", \"meter\": \"4/4\", \"section\": null},\n\
+             \x20   {\"bar\": 2, \"label\": \"2\", \"start\": 4.000000, \"end\": 5.000000, \"position\": \"2\", \"tempo\": 120, \"beat\": \"1/4\", \"meter\": \"2/4\", \"section\": null}\n\
+             \x20 ],\n  \"sections\": []\n}\n"
+        );
+    }
+
+    #[test]
+    fn sections() {
+        let s = "#count-in 1 [A] | q q q q | h | [Intro, 2] | h |%2";
+        let score = Score::new(scan(s.to_string()).unwrap()).unwrap();
+        assert_eq!(
+            score.audacity_labels(),
+            "0.000000\t2.000000\tCount-in\n\
+             2.000000\t5.000000\tA\n\
+             2.000000\t4.000000\t1\n\
+             4.000000\t5.000000\t2\n\
+             5.000000\t7.000000\tIntro, 2\n\
+             5.000000\t6.000000\t3\n\
+             6.000000\t7.000000\t4\n"
+        );
+        assert_eq!(
+            score.reaper_markers_csv(),
+            "#,Name,Start,End,Length\n\
+             M1,Count-in,0.000000,,\n\
+             M2,1,2.000000,,\n\
+             M3,2,4.000000,,\n\
+             M4,3,5.000000,,\n\
+             M5,4,6.000000,,\n\
+             R1,A,2.000000,5.000000,3.000000\n\
+             R2,\"Intro, 2\",5.000000,7.000000,2.000000\n"
+        );
+        let json = score.timeline_json();
+        assert!(json.contains("\"meter\": \"4/4\", \"section\": \"A\"}"));
+        assert!(json.ends_with(
+            "\"sections\": [\n\
+             \x20   {\"label\": \"A\", \"start\": 2.000000, \"end\": 5.000000},\n\
+             \x20   {\"label\": \"Intro, 2\", \"start\": 5.000000, \"end\": 7.000000}\n\
+             \x20 ]\n}\n"
+        ));
+        assert!(score.tempo_map_csv().ends_with(
+            "3,5.000000,5/2,120,1/4,2/4,2,\"Intro, 2\"\n\
+             4,6.000000,3,120,1/4,2/4,2,\n"
+        ));
+    }
+}
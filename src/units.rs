@@ -1,162 +1,489 @@
+use std::{
+    cmp::Ordering,
+    fmt,
+    ops::{Add, AddAssign, Div, Mul, Sub},
+};
+
 use crate::{
-    error::{ConversionError, MetrumError},
+    error::ConversionError,
     scanner::Token,
-    score::Tempo,
+    score::{Curve, Ramp, Tempo, TempoMark},
 };
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+/// A non-negative fraction that is always kept in its lowest terms. Used wherever note lengths
+/// have to be compared or summed exactly.
+pub struct Rational {
+    num: u64,
+    den: u64,
+}
+
+fn gcd(mut a: u128, mut b: u128) -> u128 {
+    while b != 0 {
+        (a, b) = (b, a % b);
+    }
+    a
+}
+
+impl Rational {
+    /// Creates a new rational from a numerator and a non-zero denominator
+    pub fn new(num: u64, den: u64) -> Self {
+        Rational::reduce(num as u128, den as u128)
+    }
+
+    pub fn zero() -> Self {
+        Rational { num: 0, den: 1 }
+    }
+
+    pub fn numer(&self) -> u64 {
+        self.num
+    }
+
+    pub fn denom(&self) -> u64 {
+        self.den
+    }
+
+    pub fn is_zero(&self) -> bool {
+        self.num == 0
+    }
+
+    pub fn as_f64(&self) -> f64 {
+        self.num as f64 / self.den as f64
+    }
+
+    fn reduce(num: u128, den: u128) -> Self {
+        assert!(den != 0, "a rational cannot have a denominator of 0");
+        let divisor = gcd(num, den).max(1);
+        match (u64::try_from(num / divisor), u64::try_from(den / divisor)) {
+            (Ok(num), Ok(den)) => Rational { num, den },
+            // too fine to be kept exactly, which only happens with very unusual scores
+            _ => Rational::approximate(num as f64 / den as f64),
+        }
+    }
+}
+
+impl Rational {
+    const PRECISION: u64 = 1_000_000_000;
+
+    /// Approximates a floating point number to the nearest billionth. Numbers too large to be
+    /// represented are clamped.
+    pub fn approximate(n: f64) -> Self {
+        Rational::new(
+            (n.max(0.0) * Self::PRECISION as f64).round() as u64,
+            Self::PRECISION,
+        )
+    }
+
+    /// Approximates a floating point number to the nearest billionth like
+    /// [Rational::approximate], but returns `None` instead if the result would be 0 or too large
+    /// to be represented
+    pub fn approximate_positive(n: f64) -> Option<Self> {
+        let billionths = (n * Self::PRECISION as f64).round();
+        match billionths >= 1.0 && billionths < u64::MAX as f64 {
+            true => Some(Rational::new(billionths as u64, Self::PRECISION)),
+            false => None,
+        }
+    }
+}
+
+impl From<u64> for Rational {
+    fn from(n: u64) -> Self {
+        Rational { num: n, den: 1 }
+    }
+}
+
+impl Add for Rational {
+    type Output = Rational;
+    fn add(self, rhs: Rational) -> Rational {
+        Rational::reduce(
+            self.num as u128 * rhs.den as u128 + rhs.num as u128 * self.den as u128,
+            self.den as u128 * rhs.den as u128,
+        )
+    }
+}
+
+impl AddAssign for Rational {
+    fn add_assign(&mut self, rhs: Rational) {
+        *self = *self + rhs;
+    }
+}
+
+impl Sub for Rational {
+    type Output = Rational;
+    /// Panics if `rhs` is larger than `self`
+    fn sub(self, rhs: Rational) -> Rational {
+        Rational::reduce(
+            self.num as u128 * rhs.den as u128 - rhs.num as u128 * self.den as u128,
+            self.den as u128 * rhs.den as u128,
+        )
+    }
+}
+
+impl Mul for Rational {
+    type Output = Rational;
+    fn mul(self, rhs: Rational) -> Rational {
+        Rational::reduce(
+            self.num as u128 * rhs.num as u128,
+            self.den as u128 * rhs.den as u128,
+        )
+    }
+}
+
+impl Div for Rational {
+    type Output = Rational;
+    /// Panics if `rhs` is zero
+    fn div(self, rhs: Rational) -> Rational {
+        Rational::reduce(
+            self.num as u128 * rhs.den as u128,
+            self.den as u128 * rhs.num as u128,
+        )
+    }
+}
+
+impl PartialOrd for Rational {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Rational {
+    fn cmp(&self, other: &Self) -> Ordering {
+        (self.num as u128 * other.den as u128).cmp(&(other.num as u128 * self.den as u128))
+    }
+}
+
+impl fmt::Display for Rational {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.den {
+            1 => write!(f, "{}", self.num),
+            _ => write!(f, "{}/{}", self.num, self.den),
+        }
+    }
+}
+
 impl Tempo {
     /// Calculates how many whole notes per minute given the current tempo
-    pub fn wholes_per_min(&self) -> f32 {
-        let tempo_ratio = self.beat.0 as f32 / self.beat.1 as f32;
-        tempo_ratio * self.num_beats as f32
+    pub fn wholes_per_min(&self) -> Rational {
+        self.beat * self.num_beats
     }
 
     /// Calculates the length of a whole note in this tempo in ms
-    pub fn duration_of_whole(&self) -> f32 {
-        60.0 * 1000.0 / self.wholes_per_min()
-    }
-
-    pub fn relative_to(&self, ratio1: &Token, ratio2: &Token) -> Result<Self, MetrumError> {
-        match ratio1 {
-            Token::Ratio(t1, b1) => match ratio2 {
-                Token::Ratio(t2, b2) => {
-                    let nums = self.wholes_per_min() / (*t1 as f32 / *b1 as f32);
-                    Ok(Tempo {
-                        beat: (*t2, *b2),
-                        num_beats: nums as u16,
-                    })
+    pub fn duration_of_whole(&self) -> Rational {
+        Rational::from(60 * 1000) / self.wholes_per_min()
+    }
+
+    /// Calculates the length in ms of a note that is `value` whole notes long
+    pub fn duration_of(&self, value: Rational) -> Rational {
+        self.duration_of_whole() * value
+    }
+
+    /// Creates the tempo after a relative tempo change, in which a note of length `to` takes as
+    /// long as a note of length `from` did in this tempo. For example, `q=q.` is a change from a
+    /// quarter to a dotted quarter note.
+    pub fn relative_to(&self, from: Rational, to: Rational) -> Self {
+        Tempo {
+            beat: to,
+            num_beats: self.wholes_per_min() / from,
+        }
+    }
+
+    /// Creates a tempo that is `factor` times as fast, in the same beat
+    pub fn scaled(&self, factor: Rational) -> Self {
+        Tempo {
+            num_beats: self.num_beats * factor,
+            ..*self
+        }
+    }
+}
+
+impl Ramp {
+    /// Calculates the time in ms it takes to play `length` whole notes from the start of a ramp
+    /// that begins at `tempo`. The ramp lasts `ramp_length` whole notes. Because the tempo changes
+    /// continuously, the result is approximated.
+    pub fn elapsed_ms(&self, tempo: &Tempo, ramp_length: Rational, length: Rational) -> Rational {
+        let beat = tempo.beat.as_f64();
+        let from = tempo.num_beats.as_f64();
+        let to = self.target.as_f64();
+        let ramp_length = ramp_length.as_f64();
+        let x = length.as_f64();
+        // minutes per whole note are 1 / (beat * bpm), integrated over the ramp
+        let minutes = if from == to || ramp_length == 0.0 {
+            x / (beat * from)
+        } else {
+            match self.curve {
+                Curve::Linear => {
+                    let bpm = from + (to - from) * x / ramp_length;
+                    ramp_length / (beat * (to - from)) * (bpm / from).ln()
+                }
+                Curve::Exponential => {
+                    let ratio = to / from;
+                    ramp_length / (beat * from * ratio.ln()) * (1.0 - ratio.powf(-x / ramp_length))
                 }
-                _ => Err(MetrumError::ConversionError(ConversionError::NonRatio)),
-            },
-            _ => return Err(MetrumError::ConversionError(ConversionError::NonRatio)),
+            }
+        };
+        Rational::approximate(minutes * 60.0 * 1000.0)
+    }
+
+    /// Calculates the number of beats per minute `length` whole notes into a ramp that begins at
+    /// `tempo` and lasts `ramp_length` whole notes. The result is approximated.
+    pub fn num_beats_at(&self, tempo: &Tempo, ramp_length: Rational, length: Rational) -> Rational {
+        let from = tempo.num_beats.as_f64();
+        let to = self.target.as_f64();
+        let progress = match ramp_length.is_zero() {
+            true => 1.0,
+            false => (length.as_f64() / ramp_length.as_f64()).min(1.0),
+        };
+        let bpm = match self.curve {
+            Curve::Linear => from + (to - from) * progress,
+            Curve::Exponential => from * (to / from).powf(progress),
+        };
+        Rational::approximate(bpm)
+    }
+}
+
+impl TempoMark {
+    /// Calculates the time in ms it takes to play `length` whole notes from this mark onwards,
+    /// assuming there is no other tempo change in between
+    pub fn elapsed_ms(&self, length: Rational) -> Rational {
+        match self.ramp {
+            None => self.tempo.duration_of(length),
+            Some(ramp) => {
+                let ramp_length = ramp.end.max(self.position) - self.position;
+                if length <= ramp_length {
+                    return ramp.elapsed_ms(&self.tempo, ramp_length, length);
+                }
+                let after = Tempo::new(self.tempo.beat, ramp.target);
+                ramp.elapsed_ms(&self.tempo, ramp_length, ramp_length)
+                    + after.duration_of(length - ramp_length)
+            }
+        }
+    }
+
+    /// Calculates the tempo `length` whole notes after this mark, assuming there is no other
+    /// tempo change in between
+    pub fn tempo_at(&self, length: Rational) -> Tempo {
+        match self.ramp {
+            None => self.tempo,
+            Some(ramp) => {
+                let ramp_length = ramp.end.max(self.position) - self.position;
+                if length >= ramp_length {
+                    return Tempo::new(self.tempo.beat, ramp.target);
+                }
+                let num_beats = ramp.num_beats_at(&self.tempo, ramp_length, length);
+                Tempo::new(self.tempo.beat, num_beats)
+            }
         }
     }
 }
 
 impl Token {
-    /// Converts a ratio to a duration. If token is not a ratio, returns a conversion error.
-    pub fn as_duration_ms(&self, tempo: &Tempo, num_dots: u16) -> Result<f32, ConversionError> {
+    /// The length of a ratio in whole notes after applying `num_dots` dots
+    pub fn as_value(&self, num_dots: u16) -> Result<Rational, ConversionError> {
         match self {
             Token::Ratio(top, bottom) => {
-                let duration_of_whole = tempo.duration_of_whole();
-                let duration = duration_of_whole * (*top as f32 / *bottom as f32);
-                let mut multiplier = 1.0;
-                for i in 0..num_dots {
-                    multiplier += 0.5 / (i as f32 + 1.0);
+                let mut value = Rational::new(*top as u64, *bottom as u64);
+                let mut extension = value;
+                for _ in 0..num_dots {
+                    extension = extension / Rational::from(2);
+                    value += extension;
                 }
-                Ok(duration * multiplier)
+                Ok(value)
             }
             _ => Err(ConversionError::NonRatioToDuration),
         }
     }
 
-    pub fn apply_dots(&self, num_dots: u16) -> Result<Token, MetrumError> {
+    /// Reads a whole or decimal number as a number of beats per minute. Returns `None` for any
+    /// other token, since a ratio after `=` is a note.
+    pub fn as_beats_per_minute(&self) -> Option<Rational> {
         match self {
-            Token::Ratio(top, bottom) => {
-                let mut top = *top;
-                let mut new_top = top;
-                let mut bottom = *bottom;
-                let mut divisor = 2;
-                for _ in 0..num_dots {
-                    if top % divisor != 0 {
-                        new_top *= 2;
-                        top *= 2;
-                        bottom *= 2;
-                    }
-                    new_top += top / divisor;
-                    divisor *= 2;
-                }
-                Ok(Token::Ratio(new_top, bottom))
-            }
-            _ => Err(MetrumError::ConversionError(ConversionError::NonRatio)),
+            Token::Number(n) => Some(Rational::from(*n as u64)),
+            Token::Decimal(whole, fraction, places) => Some(
+                Rational::from(*whole as u64) + Rational::new(*fraction as u64, 10u64.pow(*places)),
+            ),
+            _ => None,
         }
     }
 }
 
 /// returns the number of samples needed to cover the given duration in the given sample rate
-pub fn ms_to_samples(duration_ms: f32, sample_rate: u32) -> u32 {
-    (duration_ms / 1000.0 * sample_rate as f32) as u32
+pub fn ms_to_samples(duration_ms: Rational, sample_rate: u32) -> u32 {
+    let samples = duration_ms * Rational::from(sample_rate as u64) / Rational::from(1000);
+    (samples.numer() / samples.denom()) as u32
 }
 
 #[cfg(test)]
 mod tests {
     mod tempo {
-        use crate::{scanner::Token, score::Tempo};
+        use crate::{score::Tempo, units::Rational};
+
+        fn tempo(top: u64, bottom: u64, num_beats: u64) -> Tempo {
+            Tempo::new(Rational::new(top, bottom), Rational::from(num_beats))
+        }
 
         #[test]
         fn whole_duration() {
-            let data = vec![
-                (Tempo::new((1, 4), 120), 2000.0),
-                (Tempo::new((1, 1), 60), 1000.0),
-                (Tempo::new((1, 2), 60), 2000.0),
-                (Tempo::new((1, 4), 60), 4000.0),
-                (Tempo::new((1, 8), 60), 8000.0),
+            let data = [
+                (tempo(1, 4, 120), 2000),
+                (tempo(1, 1, 60), 1000),
+                (tempo(1, 2, 60), 2000),
+                (tempo(1, 4, 60), 4000),
+                (tempo(1, 8, 60), 8000),
             ];
             for (tempo, duration) in data.iter() {
-                assert_eq!(tempo.duration_of_whole(), *duration);
+                assert_eq!(tempo.duration_of_whole(), Rational::from(*duration));
             }
         }
 
         #[test]
         fn relative_tempo_change() {
-            let data = vec![
+            let data = [
                 (
-                    Tempo::new((1, 4), 120),
-                    Token::Ratio(1, 4),
-                    Token::Ratio(2, 4),
-                    Tempo::new((2, 4), 120),
+                    tempo(1, 4, 120),
+                    Rational::new(1, 4),
+                    Rational::new(2, 4),
+                    tempo(2, 4, 120),
                 ),
                 (
-                    Tempo::new((1, 2), 60),
-                    Token::Ratio(1, 4),
-                    Token::Ratio(3, 8),
-                    Tempo::new((3, 8), 120),
+                    tempo(1, 2, 60),
+                    Rational::new(1, 4),
+                    Rational::new(3, 8),
+                    tempo(3, 8, 120),
+                ),
+                (
+                    tempo(1, 4, 100),
+                    Rational::new(3, 8),
+                    Rational::new(1, 4),
+                    Tempo::new(Rational::new(1, 4), Rational::new(200, 3)),
                 ),
             ];
-            for (tempo1, ratio1, ratio2, tempo2) in data.iter() {
-                assert_eq!(tempo1.relative_to(ratio1, ratio2).unwrap(), *tempo2);
+            for (tempo1, from, to, tempo2) in data.iter() {
+                assert_eq!(tempo1.relative_to(*from, *to), *tempo2);
             }
         }
     }
 
     mod ratio {
-        use crate::{scanner::Token, score::Tempo};
+        use crate::{scanner::Token, score::Tempo, units::Rational};
 
         #[test]
         fn duration() {
-            let data = vec![
-                (Tempo::new((1, 4), 120), Token::Ratio(1, 4), 0, 500.0),
-                (Tempo::new((1, 4), 120), Token::Ratio(1, 4), 1, 750.0),
-                (Tempo::new((1, 4), 60), Token::Ratio(1, 4), 0, 1000.0),
-                (Tempo::new((1, 4), 60), Token::Ratio(1, 4), 2, 1750.0),
-                (Tempo::new((1, 4), 240), Token::Ratio(1, 4), 0, 250.0),
-                (Tempo::new((1, 4), 120), Token::Ratio(5, 4), 0, 2500.0),
-                (Tempo::new((1, 4), 120), Token::Ratio(10, 8), 0, 2500.0),
+            let quarter = Rational::new(1, 4);
+            let data = [
+                (120, Token::Ratio(1, 4), 0, Rational::from(500)),
+                (120, Token::Ratio(1, 4), 1, Rational::from(750)),
+                (60, Token::Ratio(1, 4), 0, Rational::from(1000)),
+                (60, Token::Ratio(1, 4), 2, Rational::from(1750)),
+                (60, Token::Ratio(1, 4), 3, Rational::from(1875)),
+                (240, Token::Ratio(1, 4), 0, Rational::from(250)),
+                (120, Token::Ratio(5, 4), 0, Rational::from(2500)),
+                (120, Token::Ratio(10, 8), 0, Rational::from(2500)),
+                (120, Token::Ratio(1, 3), 0, Rational::new(2000, 3)),
             ];
-            for (tempo, ratio, num_dots, duration) in data.iter() {
-                assert_eq!(ratio.as_duration_ms(tempo, *num_dots).unwrap(), *duration);
+            for (num_beats, ratio, num_dots, duration) in data.iter() {
+                let tempo = Tempo::new(quarter, Rational::from(*num_beats));
+                let value = ratio.as_value(*num_dots).unwrap();
+                assert_eq!(tempo.duration_of(value), *duration);
             }
         }
 
         #[test]
         fn dot_application() {
-            let data = vec![
-                (Token::Ratio(1, 4), 1, Token::Ratio(3, 8)),
-                (Token::Ratio(1, 4), 2, Token::Ratio(7, 16)),
-                (Token::Ratio(3, 8), 1, Token::Ratio(9, 16)),
+            let data = [
+                (Token::Ratio(1, 4), 1, Rational::new(3, 8)),
+                (Token::Ratio(1, 4), 2, Rational::new(7, 16)),
+                (Token::Ratio(3, 8), 1, Rational::new(9, 16)),
+            ];
+            for (ratio, num_dots, value) in data.iter() {
+                assert_eq!(ratio.as_value(*num_dots).unwrap(), *value);
+            }
+        }
+    }
+
+    mod rational {
+        use crate::{scanner::Token, units::Rational};
+
+        #[test]
+        fn arithmetic() {
+            let half = Rational::new(1, 2);
+            let third = Rational::new(1, 3);
+            assert_eq!(half + third, Rational::new(5, 6));
+            assert_eq!(half - third, Rational::new(1, 6));
+            assert_eq!(half * third, Rational::new(1, 6));
+            assert_eq!(half / third, Rational::new(3, 2));
+            assert_eq!(Rational::new(4, 8), half);
+            assert!(third < half);
+            assert_eq!(Rational::new(6, 3).to_string(), "2");
+            assert_eq!(Rational::new(6, 8).to_string(), "3/4");
+        }
+
+        #[test]
+        fn overflow() {
+            let tiny = Rational::new(1, u64::MAX - 1);
+            assert_eq!(tiny * tiny, Rational::zero());
+            assert_eq!(
+                tiny + Rational::new(1, 3),
+                Rational::new(333_333_333, 1_000_000_000)
+            );
+        }
+
+        #[test]
+        fn approximation() {
+            let data = [
+                (0.8, Some(Rational::new(4, 5))),
+                (0.000000001, Some(Rational::new(1, 1_000_000_000))),
+                (0.0000000001, None),
+                (0.0, None),
+                (-1.0, None),
+                (1e20, None),
+                (f64::NAN, None),
+                (f64::INFINITY, None),
+            ];
+            for (n, rational) in data.iter() {
+                assert_eq!(Rational::approximate_positive(*n), *rational, "{n}");
+            }
+        }
+
+        #[test]
+        fn values() {
+            let data = [
+                (Token::Ratio(1, 4), 0, Rational::new(1, 4)),
+                (Token::Ratio(1, 4), 1, Rational::new(3, 8)),
+                (Token::Ratio(1, 4), 2, Rational::new(7, 16)),
+                (Token::Ratio(2, 6), 1, Rational::new(1, 2)),
+            ];
+            for (ratio, num_dots, value) in data.iter() {
+                assert_eq!(ratio.as_value(*num_dots).unwrap(), *value);
+            }
+        }
+
+        #[test]
+        fn beats_per_minute() {
+            let data = [
+                (Token::Number(92), Some(Rational::from(92))),
+                (Token::Decimal(92, 5, 1), Some(Rational::new(185, 2))),
+                (Token::Decimal(60, 25, 3), Some(Rational::new(12005, 200))),
+                (Token::Ratio(184, 2), None),
+                (Token::Ratio(1, 4), None),
+                (Token::Ratio(4, 1), None),
+                (Token::Dot, None),
             ];
-            for (ratio1, num_dots, ratio2) in data.iter() {
-                assert_eq!(ratio1.apply_dots(*num_dots).unwrap(), *ratio2);
+            for (token, bpm) in data.iter() {
+                assert_eq!(token.as_beats_per_minute(), *bpm, "{token:?}");
             }
         }
     }
 
     #[test]
     fn num_samples_from_duration() {
-        let data = vec![
-            (1000.0, 44100, 44100),
-            (500.0, 44100, 22050),
-            (2000.0, 44100, 88200),
+        use super::Rational;
+        let data = [
+            (Rational::from(1000), 44100, 44100),
+            (Rational::from(500), 44100, 22050),
+            (Rational::from(2000), 44100, 88200),
+            (Rational::new(2000, 3), 44100, 29400),
+            (Rational::new(1000, 3), 48000, 16000),
+            (Rational::new(1, 3), 44100, 14),
         ];
         for (dur, sample_rate, num_samples) in data.iter() {
             assert_eq!(super::ms_to_samples(*dur, *sample_rate), *num_samples)
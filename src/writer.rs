@@ -1,65 +1,526 @@
-use crate::score::Score;
+use std::fs::File;
+use std::io::{BufWriter, Read, Seek, Write};
+use std::path::{Path, PathBuf};
+
+use crate::error::SoundError;
+use crate::score::{Accent, ClickKind, Score};
+use crate::synth::SynthClicks;
 use crate::units;
 
 const WEAK: &[u8; 65536] = include_bytes!("../assets/digital/weak.wav");
 const STRONG: &[u8; 65536] = include_bytes!("../assets/digital/strong.wav");
 
+/// Gain applied to the strong sample for secondary beats
+pub(crate) const SECONDARY_GAIN: f32 = 0.6;
+/// Gain applied to the weak sample for ghost notes
+pub(crate) const GHOST_GAIN: f32 = 0.3;
+/// Gain applied to the weak sample for subdivision clicks
+pub(crate) const SUBDIVISION_GAIN: f32 = 0.2;
+
+/// The sample rate of the embedded click samples
+const SAMPLE_RATE: u32 = 44100;
+
+/// The sounds of a sound pack. In a sound pack directory, each is looked up as `<sound>.wav`.
+const SOUNDS: [&str; 6] = [
+    "downbeat",
+    "secondary",
+    "unaccented",
+    "ghost",
+    "subdivision",
+    "count-in",
+];
+
+/// Holds one click sample per audible [Accent] level, a quieter sample for subdivisions and
+/// optionally a distinct sample for the count-in, all recorded at the same sample rate
+#[derive(Debug, Clone)]
 pub struct Metronome {
-    pub weak: Vec<i16>,
-    pub strong: Vec<i16>,
+    pub sample_rate: u32,
+    pub downbeat: Vec<i16>,
+    pub secondary: Vec<i16>,
+    pub unaccented: Vec<i16>,
+    pub ghost: Vec<i16>,
+    pub subdivision: Vec<i16>,
+    pub count_in: Option<Vec<i16>>,
+}
+
+impl Default for Metronome {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Metronome {
     pub fn new() -> Self {
-        let weak = hound::WavReader::new(std::io::BufReader::new(&WEAK[..]))
-            .unwrap()
-            .samples::<i16>()
-            .into_iter()
-            .map(|sample| sample.unwrap())
-            .collect::<Vec<i16>>();
-        let strong = hound::WavReader::new(std::io::BufReader::new(&STRONG[..]))
-            .unwrap()
-            .samples::<i16>()
-            .into_iter()
-            .map(|sample| sample.unwrap())
-            .collect::<Vec<i16>>();
-        Metronome { strong, weak }
+        let (weak, _) = decode(hound::WavReader::new(&WEAK[..]).unwrap()).unwrap();
+        let (strong, _) = decode(hound::WavReader::new(&STRONG[..]).unwrap()).unwrap();
+        Metronome {
+            secondary: with_gain(&strong, SECONDARY_GAIN),
+            ghost: with_gain(&weak, GHOST_GAIN),
+            subdivision: with_gain(&weak, SUBDIVISION_GAIN),
+            downbeat: strong,
+            unaccented: weak,
+            count_in: None,
+            sample_rate: SAMPLE_RATE,
+        }
+    }
+
+    /// Creates a metronome with synthesized clicks, rendered at the given sample rate
+    pub fn synthesized(clicks: &SynthClicks, sample_rate: u32) -> Self {
+        Metronome {
+            downbeat: clicks.downbeat.render(sample_rate),
+            secondary: clicks.secondary.render(sample_rate),
+            unaccented: clicks.unaccented.render(sample_rate),
+            ghost: clicks.ghost.render(sample_rate),
+            subdivision: clicks.subdivision.render(sample_rate),
+            count_in: None,
+            sample_rate,
+        }
+    }
+
+    /// Loads a sound pack, either from a directory containing a `<sound>.wav` file per sound or
+    /// from a manifest with a `<sound> = <path>` line per sound. Paths in a manifest are relative
+    /// to the manifest and lines starting with `#` are ignored. The sounds are `downbeat`,
+    /// `secondary`, `unaccented`, `ghost`, `subdivision` and `count-in`, of which only
+    /// `downbeat` and `unaccented` are required. Missing secondary, ghost and subdivision sounds
+    /// are made from the required ones, like the embedded clicks. Samples can be in any format
+    /// and are mixed down to mono and resampled to the sample rate of the downbeat.
+    pub fn from_path(path: &Path) -> Result<Self, SoundError> {
+        let files = match path.is_dir() {
+            true => SOUNDS
+                .iter()
+                .map(|sound| (*sound, path.join(format!("{sound}.wav"))))
+                .filter(|(_, file)| file.exists())
+                .collect(),
+            false => read_manifest(path)?,
+        };
+        let mut samples = Vec::new();
+        for (sound, file) in files {
+            samples.push((sound, load(&file)?));
+        }
+        let find = |sound: &str| {
+            samples
+                .iter()
+                .rev()
+                .find(|(s, _)| *s == sound)
+                .map(|(_, sample)| sample)
+        };
+        let (downbeat, sample_rate) =
+            find("downbeat").ok_or(SoundError::MissingSound("downbeat"))?;
+        let sample_rate = *sample_rate;
+        let get =
+            |sound: &str| find(sound).map(|(click, rate)| resample(click, *rate, sample_rate));
+        let unaccented = get("unaccented").ok_or(SoundError::MissingSound("unaccented"))?;
+        Ok(Metronome {
+            sample_rate,
+            secondary: get("secondary").unwrap_or_else(|| with_gain(downbeat, SECONDARY_GAIN)),
+            ghost: get("ghost").unwrap_or_else(|| with_gain(&unaccented, GHOST_GAIN)),
+            subdivision: get("subdivision")
+                .unwrap_or_else(|| with_gain(&unaccented, SUBDIVISION_GAIN)),
+            count_in: get("count-in"),
+            downbeat: downbeat.clone(),
+            unaccented,
+        })
+    }
+
+    /// Uses a distinct click for every beat of the count-in: the downbeat sample played an octave
+    /// higher. Keeps the count-in sound of a sound pack if it has one.
+    pub fn with_distinct_count_in(mut self) -> Self {
+        if self.count_in.is_none() {
+            self.count_in = Some(self.downbeat.iter().step_by(2).copied().collect());
+        }
+        self
+    }
+
+    /// Returns the click sample for the given accent, or `None` if the accent is not heard
+    pub fn click(&self, accent: Accent) -> Option<&[i16]> {
+        match accent {
+            Accent::Downbeat => Some(&self.downbeat),
+            Accent::Secondary => Some(&self.secondary),
+            Accent::Unaccented => Some(&self.unaccented),
+            Accent::Ghost => Some(&self.ghost),
+            Accent::Silent => None,
+        }
+    }
+
+    /// Returns a copy of the metronome with every click resampled to the given sample rate
+    pub fn resampled(&self, sample_rate: u32) -> Self {
+        let from = self.sample_rate;
+        Metronome {
+            sample_rate,
+            downbeat: resample(&self.downbeat, from, sample_rate),
+            secondary: resample(&self.secondary, from, sample_rate),
+            unaccented: resample(&self.unaccented, from, sample_rate),
+            ghost: resample(&self.ghost, from, sample_rate),
+            subdivision: resample(&self.subdivision, from, sample_rate),
+            count_in: self
+                .count_in
+                .as_ref()
+                .map(|click| resample(click, from, sample_rate)),
+        }
+    }
+}
+
+fn with_gain(samples: &[i16], gain: f32) -> Vec<i16> {
+    samples
+        .iter()
+        .map(|sample| (*sample as f32 * gain) as i16)
+        .collect()
+}
+
+/// Reads every sample of a wav file as a 16-bit integer, mixing all channels down to mono.
+/// Returns the samples and their sample rate.
+fn decode<R: Read>(reader: hound::WavReader<R>) -> Result<(Vec<i16>, u32), hound::Error> {
+    let spec = reader.spec();
+    let samples = match spec.sample_format {
+        hound::SampleFormat::Float => reader
+            .into_samples::<f32>()
+            .collect::<Result<Vec<_>, _>>()?,
+        hound::SampleFormat::Int => {
+            let full_scale = (1u32 << (spec.bits_per_sample - 1)) as f32;
+            reader
+                .into_samples::<i32>()
+                .map(|sample| sample.map(|s| s as f32 / full_scale))
+                .collect::<Result<Vec<_>, _>>()?
+        }
+    };
+    let mono = samples
+        .chunks(spec.channels as usize)
+        .map(|frame| {
+            let mean = frame.iter().sum::<f32>() / frame.len() as f32;
+            (mean * 32768.0).clamp(i16::MIN as f32, i16::MAX as f32) as i16
+        })
+        .collect();
+    Ok((mono, spec.sample_rate))
+}
+
+/// Loads a sample of a sound pack
+fn load(path: &Path) -> Result<(Vec<i16>, u32), SoundError> {
+    let error = |e| SoundError::Sample(path.to_path_buf(), e);
+    let (samples, sample_rate) =
+        decode(hound::WavReader::open(path).map_err(error)?).map_err(error)?;
+    if samples.is_empty() {
+        return Err(SoundError::EmptySample(path.to_path_buf()));
+    }
+    Ok((samples, sample_rate))
+}
+
+/// Reads the sounds and the paths of their samples from a sound pack manifest
+fn read_manifest(path: &Path) -> Result<Vec<(&'static str, PathBuf)>, SoundError> {
+    let text =
+        std::fs::read_to_string(path).map_err(|e| SoundError::Manifest(path.to_path_buf(), e))?;
+    let dir = path.parent().unwrap_or(Path::new(""));
+    let mut files = Vec::new();
+    for (i, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let entry = line.split_once('=').and_then(|(sound, file)| {
+            let sound = SOUNDS.iter().find(|s| **s == sound.trim())?;
+            Some((*sound, dir.join(file.trim())))
+        });
+        match entry {
+            Some(entry) => files.push(entry),
+            None => return Err(SoundError::ManifestLine(i + 1, line.to_string())),
+        }
+    }
+    Ok(files)
+}
+
+/// Mixes clicks, each given with the sample it starts at, into a buffer of at least `len`
+/// samples. Overlapping clicks are summed and saturate at the limits of a 16-bit sample. The
+/// buffer is extended to let the last clicks ring out.
+fn mix(clicks: &[(usize, &[i16])], len: usize) -> Vec<i16> {
+    let end = clicks
+        .iter()
+        .map(|(position, click)| position + click.len())
+        .fold(len, usize::max);
+    let mut buf: Vec<i32> = vec![0; end];
+    for (position, click) in clicks {
+        for (out, sample) in buf[*position..].iter_mut().zip(click.iter()) {
+            *out += *sample as i32;
+        }
+    }
+    buf.iter()
+        .map(|sample| (*sample).clamp(i16::MIN as i32, i16::MAX as i32) as i16)
+        .collect()
+}
+
+/// Resamples a click from one sample rate to another by linear interpolation
+fn resample(samples: &[i16], from: u32, to: u32) -> Vec<i16> {
+    if from == to {
+        return samples.to_vec();
+    }
+    let len = (samples.len() as u64 * to as u64 / from as u64) as usize;
+    (0..len)
+        .map(|i| {
+            let position = i as f64 * from as f64 / to as f64;
+            let index = position as usize;
+            let a = samples[index] as f64;
+            let b = samples.get(index + 1).copied().unwrap_or(0) as f64;
+            (a + (b - a) * position.fract()).round() as i16
+        })
+        .collect()
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// The sample format of a rendered click track
+pub enum BitDepth {
+    Int16,
+    Int24,
+    Float32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+/// Describes the audio format a click track is written in. The click is panned between the first
+/// two channels, from -1.0 (left) to 1.0 (right). Any further channels get the unpanned click.
+pub struct RenderOptions {
+    pub sample_rate: u32,
+    pub bit_depth: BitDepth,
+    pub channels: u16,
+    pub pan: f32,
+}
+
+impl Default for RenderOptions {
+    fn default() -> Self {
+        RenderOptions {
+            sample_rate: SAMPLE_RATE,
+            bit_depth: BitDepth::Int16,
+            channels: 1,
+            pan: 0.0,
+        }
+    }
+}
+
+impl RenderOptions {
+    fn spec(&self) -> hound::WavSpec {
+        let (bits_per_sample, sample_format) = match self.bit_depth {
+            BitDepth::Int16 => (16, hound::SampleFormat::Int),
+            BitDepth::Int24 => (24, hound::SampleFormat::Int),
+            BitDepth::Float32 => (32, hound::SampleFormat::Float),
+        };
+        hound::WavSpec {
+            channels: self.channels,
+            sample_rate: self.sample_rate,
+            bits_per_sample,
+            sample_format,
+        }
+    }
+
+    /// The gain of every channel. Panning keeps the louder side at full level, so a centered
+    /// click is as loud on both channels as it is in mono.
+    fn channel_gains(&self) -> Vec<f32> {
+        let pan = self.pan.clamp(-1.0, 1.0);
+        (0..self.channels)
+            .map(|channel| match (self.channels, channel) {
+                (1, _) => 1.0,
+                (_, 0) => (1.0 - pan).min(1.0),
+                (_, 1) => (1.0 + pan).min(1.0),
+                _ => 1.0,
+            })
+            .collect()
     }
 }
 
 impl Score {
     /// Converts the score to raw audio data that will be used for writing the score later
     pub fn wav_buffer(&self, sample_rate: u32) -> Vec<i16> {
-        let metronome = Metronome::new();
-        let num_samples = units::ms_to_samples(self.total_duration(), sample_rate) as usize;
-        let mut buf: Vec<i16> = vec![0; num_samples];
-        let mut position = 0;
-        for bar in self.bars.iter() {
-            for dur in bar.durations.iter() {
-                let beat = match dur.strong {
-                    true => &metronome.strong,
-                    false => &metronome.weak,
+        self.render(&Metronome::new(), sample_rate)
+    }
+
+    /// Converts the score, preceded by its count-in, to raw audio data using the clicks of the
+    /// given metronome, which are resampled if they were recorded at another sample rate
+    pub fn render(&self, metronome: &Metronome, sample_rate: u32) -> Vec<i16> {
+        let resampled;
+        let metronome = match metronome.sample_rate == sample_rate {
+            true => metronome,
+            false => {
+                resampled = metronome.resampled(sample_rate);
+                &resampled
+            }
+        };
+        let clicks: Vec<(usize, &[i16])> = self
+            .clicks()
+            .iter()
+            .filter_map(|click| {
+                let sample = match click.kind {
+                    ClickKind::CountIn(accent) => match &metronome.count_in {
+                        Some(count_in) => Some(&count_in[..]),
+                        None => metronome.click(accent),
+                    },
+                    ClickKind::Note(accent) => metronome.click(accent),
+                    ClickKind::Subdivision => Some(&metronome.subdivision[..]),
                 };
-                for (index, sample) in beat.iter().enumerate() {
-                    buf[index + position] = *sample;
+                Some((
+                    units::ms_to_samples(click.ms, sample_rate) as usize,
+                    sample?,
+                ))
+            })
+            .collect();
+        let (_, count_in_ms) = self.count_in_length();
+        let num_samples =
+            units::ms_to_samples(count_in_ms + self.total_duration(), sample_rate) as usize;
+        mix(&clicks, num_samples)
+    }
+
+    /// writes the rhythmic score out as a click track wav file
+    pub fn write_click_track(&self, path: &str) -> Result<(), hound::Error> {
+        self.write_click_track_with(path, &Metronome::new(), &RenderOptions::default())
+    }
+
+    /// writes the rhythmic score out as a click track wav file using the clicks of the given
+    /// metronome in the given format
+    pub fn write_click_track_with(
+        &self,
+        path: &str,
+        metronome: &Metronome,
+        options: &RenderOptions,
+    ) -> Result<(), hound::Error> {
+        let file = BufWriter::new(File::create(path)?);
+        self.write_click_track_to(file, metronome, options)
+    }
+
+    /// writes the rhythmic score as a click track in the wav format to any seekable writer, such
+    /// as a file or an in-memory [std::io::Cursor]
+    pub fn write_click_track_to<W: Write + Seek>(
+        &self,
+        writer: W,
+        metronome: &Metronome,
+        options: &RenderOptions,
+    ) -> Result<(), hound::Error> {
+        let gains = options.channel_gains();
+        let mut writer = hound::WavWriter::new(writer, options.spec())?;
+        for sample in self.render(metronome, options.sample_rate).iter() {
+            for gain in gains.iter() {
+                let sample = *sample as f32 * gain;
+                match options.bit_depth {
+                    BitDepth::Int16 => writer.write_sample(sample as i16)?,
+                    BitDepth::Int24 => writer.write_sample((sample * 256.0) as i32)?,
+                    BitDepth::Float32 => writer.write_sample(sample / 32768.0)?,
                 }
-                position += units::ms_to_samples(dur.ms, sample_rate) as usize;
             }
         }
-        buf
+        writer.finalize()
     }
-    /// writes the rhythmic score out as a click track wav file
-    pub fn write_click_track(&self, path: &str) -> Result<(), hound::Error> {
-        let spec = hound::WavSpec {
-            channels: 1,
-            sample_rate: 44100,
-            bits_per_sample: 16,
-            sample_format: hound::SampleFormat::Int,
-        };
-        let mut writer = hound::WavWriter::create(path, spec)?;
-        for sample in self.wav_buffer(spec.sample_rate).iter() {
-            writer.write_sample(*sample)?
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use super::{mix, read_manifest, resample, Metronome, RenderOptions, GHOST_GAIN};
+    use crate::{error::SoundError, scanner::scan, score::Score, units::ms_to_samples};
+
+    #[test]
+    fn resampling() {
+        let data = [
+            (vec![0, 100, 200], 44100, 44100, vec![0, 100, 200]),
+            (
+                vec![0, 100, 200, 300],
+                1,
+                2,
+                vec![0, 50, 100, 150, 200, 250, 300, 150],
+            ),
+            (vec![0, 100, 200, 300], 2, 1, vec![0, 200]),
+            (vec![0; 44100], 44100, 48000, vec![0; 48000]),
+        ];
+        for (samples, from, to, expected) in data.iter() {
+            assert_eq!(resample(samples, *from, *to), *expected);
+        }
+    }
+
+    #[test]
+    fn mixing() {
+        type Clicks<'a> = &'a [(usize, &'a [i16])];
+        let data: [(Clicks, usize, Vec<i16>); 5] = [
+            (&[(0, &[1, 2]), (3, &[3])], 5, vec![1, 2, 0, 3, 0]),
+            (&[(0, &[1, 2, 3]), (1, &[10, 20])], 3, vec![1, 12, 23]),
+            (&[(1, &[1, 2, 3])], 2, vec![0, 1, 2, 3]),
+            (&[(0, &[30000]), (0, &[30000])], 1, vec![i16::MAX]),
+            (&[(0, &[-30000]), (0, &[-30000])], 1, vec![i16::MIN]),
+        ];
+        for (clicks, len, expected) in data.iter() {
+            assert_eq!(mix(clicks, *len), *expected);
+        }
+    }
+
+    #[test]
+    fn dense_scores() {
+        let metronome = Metronome::new();
+        let data = [
+            "| tx64 |",
+            "q = 300 | 1/64x64 |",
+            "| 1/128x3 |",
+            "| q | t |",
+        ];
+        for d in data.iter() {
+            let score = Score::new(scan(d.to_string()).unwrap()).unwrap();
+            let buffer = score.render(&metronome, 44100);
+            let last = ms_to_samples(*score.onsets().last().unwrap(), 44100) as usize;
+            // the last click rings out completely
+            assert_eq!(buffer.len(), last + metronome.unaccented.len(), "{d}");
+        }
+        let score = Score::new(scan("| q | t |".to_string()).unwrap()).unwrap();
+        let buffer = score.render(&metronome, 44100);
+        assert_eq!(buffer[22050..], metronome.downbeat[..]);
+    }
+
+    #[test]
+    fn channel_gains() {
+        let data = [
+            (1, 0.5, vec![1.0]),
+            (2, 0.0, vec![1.0, 1.0]),
+            (2, -1.0, vec![1.0, 0.0]),
+            (2, 0.5, vec![0.5, 1.0]),
+            (2, 3.0, vec![0.0, 1.0]),
+            (4, -0.25, vec![1.0, 0.75, 1.0, 1.0]),
+        ];
+        for (channels, pan, gains) in data.iter() {
+            let options = RenderOptions {
+                channels: *channels,
+                pan: *pan,
+                ..Default::default()
+            };
+            assert_eq!(options.channel_gains(), *gains);
         }
-        Ok(())
+    }
+
+    #[test]
+    fn sound_packs() {
+        let pack = Path::new("tests/assets/sounds");
+        let from_dir = Metronome::from_path(pack).unwrap();
+        assert_eq!(from_dir.sample_rate, 48000);
+        assert_eq!(from_dir.downbeat.len(), 480);
+        // resampled from 22.05 kHz
+        assert_eq!(from_dir.unaccented.len(), 478);
+        assert_eq!(from_dir.ghost.len(), 478);
+        assert!(from_dir.ghost[10].abs() <= (from_dir.unaccented[10] as f32 * GHOST_GAIN) as i16);
+        assert!(from_dir.count_in.is_none());
+
+        let from_manifest = Metronome::from_path(&pack.join("manifest")).unwrap();
+        assert_eq!(from_manifest.unaccented, from_dir.unaccented);
+        assert_eq!(from_manifest.count_in, Some(from_dir.downbeat.clone()));
+        assert_eq!(
+            from_manifest.with_distinct_count_in().count_in,
+            Some(from_dir.downbeat)
+        );
+    }
+
+    #[test]
+    fn invalid_sound_packs() {
+        let missing = Metronome::from_path(Path::new("assets/digital"));
+        assert!(matches!(missing, Err(SoundError::MissingSound("downbeat"))));
+        let not_a_manifest = Metronome::from_path(Path::new("tests/assets/sounds/downbeat.wav"));
+        assert!(matches!(not_a_manifest, Err(SoundError::Manifest(..))));
+        let not_a_sample = Metronome::from_path(Path::new("examples/valid/accents"));
+        assert!(matches!(not_a_sample, Err(SoundError::ManifestLine(1, _))));
+    }
+
+    #[test]
+    fn manifests() {
+        let files = read_manifest(Path::new("tests/assets/sounds/manifest")).unwrap();
+        let sounds: Vec<&str> = files.iter().map(|(sound, _)| *sound).collect();
+        assert_eq!(sounds, vec!["downbeat", "unaccented", "count-in"]);
+        assert_eq!(files[1].1, Path::new("tests/assets/sounds/unaccented.wav"));
     }
 }
@@ -2,7 +2,11 @@ extern crate test_generator;
 
 #[cfg(test)]
 mod snapshot {
-    use metrome::{scanner, score::Score};
+    use metrome::{
+        scanner,
+        score::Score,
+        writer::{Metronome, RenderOptions},
+    };
     use test_generator::test_resources;
     #[test_resources("examples/valid/*")]
     fn wav(path: &str) {
@@ -17,7 +21,7 @@ mod snapshot {
         };
         let snapshot_path = format!(
             "tests{separator}assets{separator}wav{separator}{}.wav",
-            path.split(separator).last().unwrap()
+            path.split(separator).next_back().unwrap()
         );
         let mut reader = hound::WavReader::open(snapshot_path).unwrap();
         let snapshot_buffer = reader
@@ -29,4 +33,22 @@ mod snapshot {
             assert_eq!(buffer[i], snapshot_buffer[i]);
         }
     }
+
+    #[test_resources("examples/valid/*")]
+    fn wav_bytes(path: &str) {
+        let file = std::fs::read_to_string(path).unwrap();
+        let tokens = scanner::scan(file).unwrap();
+        let score = Score::new(tokens).unwrap();
+        let mut buffer = std::io::Cursor::new(Vec::new());
+        score
+            .write_click_track_to(&mut buffer, &Metronome::new(), &RenderOptions::default())
+            .unwrap();
+
+        let name = std::path::Path::new(path).file_name().unwrap();
+        let snapshot_path = std::path::Path::new("tests")
+            .join("assets")
+            .join("wav")
+            .join(format!("{}.wav", name.to_string_lossy()));
+        assert_eq!(buffer.into_inner(), std::fs::read(snapshot_path).unwrap());
+    }
 }